@@ -0,0 +1,161 @@
+// Standard little programs, run through the parser and HeapPtr::force.
+// For each of them we report the time, what the runtime counted (see src/stats.rs)
+// and what the Rust allocator saw: every HeapPtr::new is an allocation, but closures, environments
+// and the evaluator's stack allocate too.
+//
+// Every program runs twice: with reference counted objects, and with the objects in a heap::Heap (the "/heap" rows).
+//
+// `cargo bench` runs all of them, `cargo bench -- nfib queens` only the named ones.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use call_by_need_in_rust::heap::Heap;
+use call_by_need_in_rust::{parser, stats};
+
+// Counts allocations and their bytes, the work itself is done by the system allocator.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    // A growing Vec counts as a new allocation of the new size.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Name, source and the expected result.
+const PROGRAMS: [(&str, &str, i32); 5] = [
+    (
+        "nfib",
+        "letrec nfib n = if n < 2 then 1 else nfib (n - 1) + nfib (n - 2) + 1 in
+         nfib 22",
+        57313,
+    ),
+    (
+        "tak",
+        "letrec tak x y z = if y < x then tak (tak (x - 1) y z) (tak (y - 1) z x) (tak (z - 1) x y) else z in
+         tak 18 12 6",
+        7,
+    ),
+    (
+        "primes",
+        "letrec from n = Cons n (from (n + 1)) in
+         letrec filter p xs = case xs of { Nil -> Nil; Cons h t -> if p h then Cons h (filter p t) else filter p t } in
+         letrec sieve xs = case xs of { Nil -> Nil; Cons p t -> Cons p (sieve (filter (\\x. mod x p /= 0) t)) } in
+         letrec nth n xs = case xs of { Cons h t -> if n == 0 then h else nth (n - 1) t } in
+         nth 500 (sieve (from 2))",
+        3581,
+    ),
+    (
+        "queens",
+        "letrec append xs ys = case xs of { Nil -> ys; Cons h t -> Cons h (append t ys) } in
+         letrec concatMap f xs = case xs of { Nil -> Nil; Cons h t -> append (f h) (concatMap f t) } in
+         letrec range a b = if a > b then Nil else Cons a (range (a + 1) b) in
+         letrec length xs = case xs of { Nil -> 0; Cons h t -> 1 + length t } in
+         letrec safe q d qs = case qs of {
+           Nil -> True;
+           Cons h t -> if q == h then False else if q + d == h then False else if q - d == h then False else safe q (d + 1) t
+         } in
+         letrec queens k n =
+           if k == 0 then Cons Nil Nil
+           else concatMap (\\qs. concatMap (\\q. if safe q 1 qs then Cons (Cons q qs) Nil else Nil) (range 1 n)) (queens (k - 1) n) in
+         length (queens 8 8)",
+        92,
+    ),
+    // A new closure for every number, each of them called once. Comparing `acc` keeps it evaluated.
+    (
+        "closures",
+        "letrec sum n acc = if n == 0 then acc else if acc < 0 then 0 else sum (n - 1) ((\\x. x + n) acc) in
+         sum 60000 0",
+        1800030000,
+    ),
+];
+
+// The time is the best of this many runs, the counts are the same every time.
+const RUNS: usize = 3;
+
+struct Measurement {
+    time: Duration,
+    stats: stats::Stats,
+    allocations: usize,
+    bytes: usize,
+}
+
+fn run(src: &str, expected: i32, in_heap: bool) -> Measurement {
+    stats::reset();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let eval = || parser::parse(src).unwrap().try_i32().unwrap();
+    let mut heap = Heap::new();
+    let result = if in_heap { heap.run(eval) } else { eval() };
+    drop(heap);
+    let time = start.elapsed();
+    let measurement = Measurement {
+        time,
+        stats: stats::get(),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        bytes: BYTES.load(Ordering::Relaxed) - bytes,
+    };
+    assert_eq!(result, expected);
+    measurement
+}
+
+fn main() {
+    // Cargo passes flags like --bench, the other arguments select programs.
+    let selected: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    stats::enable();
+    println!(
+        "{:<12} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "program", "time", "HeapPtrs", "forced", "peak live", "allocations", "bytes"
+    );
+    for (name, src, expected) in PROGRAMS {
+        if !selected.is_empty() && !selected.iter().any(|s| s == name) {
+            continue;
+        }
+        for in_heap in [false, true] {
+            let mut best = run(src, expected, in_heap);
+            for _ in 1..RUNS {
+                let m = run(src, expected, in_heap);
+                if m.time < best.time {
+                    best = m;
+                }
+            }
+            println!(
+                "{:<12} {:>10.2?} {:>12} {:>12} {:>12} {:>12} {:>12}",
+                if in_heap {
+                    format!("{name}/heap")
+                } else {
+                    name.to_string()
+                },
+                best.time,
+                best.stats.allocated.total(),
+                best.stats.forced,
+                best.stats.peak_live,
+                best.allocations,
+                best.bytes
+            );
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Pictures of the heap in Graphviz's DOT language, to see the sharing: an object pointed to from several places
+// is drawn once, with several arrows going into it.
+//
+//   let dot = dot::to_dot(&term);          // the heap reachable from term
+//   let (frames, value) = dot::force_frames(&term, EvalStrategy::Need);  // one picture per evaluation step
+//
+// `dot -Tsvg` renders a picture, a sequence of frames shows call-by-need at work:
+// thunks get blackholed while they are evaluated and then overwritten with indirections to their values.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{
+    Addr, Alts, ConObj, Elim, EvalError, EvalLimits, EvalStrategy, HeapObj, HeapPtr, NeutralObj,
+    Target, Value,
+};
+
+// The heap reachable from `root`.
+pub fn to_dot(root: &HeapPtr) -> String {
+    draw(&[root], None)
+}
+
+// Forces `root`, drawing the heap before every step of the evaluator and once more at the end.
+// The object the evaluator looks at is highlighted. The thunks being evaluated are blackholed,
+// so the frames also show what the evaluator's stack points to.
+pub fn force_frames(
+    root: &HeapPtr,
+    strategy: EvalStrategy,
+) -> (Vec<String>, Result<Value, EvalError>) {
+    let mut frames = vec![];
+    let result = root.try_force_with(strategy, EvalLimits::default(), |step| {
+        let mut roots = vec![root, step.current];
+        roots.extend(step.stack_ptrs());
+        frames.push(draw(&roots, Some(step.current)));
+    });
+    frames.push(to_dot(root));
+    (frames, result)
+}
+
+fn draw(roots: &[&HeapPtr], current: Option<&HeapPtr>) -> String {
+    // Nodes are numbered in the order we find them.
+    let mut ids: HashMap<Addr, usize> = HashMap::new();
+    let mut todo: Vec<HeapPtr> = vec![];
+    let mut id = |ptr: &HeapPtr, todo: &mut Vec<HeapPtr>| {
+        let next = ids.len();
+        *ids.entry(ptr.addr()).or_insert_with(|| {
+            todo.push(ptr.clone());
+            next
+        })
+    };
+
+    let mut out = String::from("digraph heap {\n  node [shape=box, fontname=\"monospace\"];\n");
+    for root in roots {
+        id(root, &mut todo);
+    }
+    if let Some(current) = current {
+        let n = id(current, &mut todo);
+        writeln!(out, "  n{n} [style=filled, fillcolor=yellow];").unwrap();
+    }
+    // Not recursive, a long chain of thunks would overflow the Rust stack.
+    let mut done = 0;
+    while done < todo.len() {
+        let ptr = todo[done].clone();
+        let n = done;
+        done += 1;
+        let (label, edges) = node(&ptr.raw());
+        writeln!(out, "  n{n} [label=\"{}\"];", escape(&label)).unwrap();
+        for (child, edge, dashed) in edges {
+            let m = id(&child, &mut todo);
+            let style = if dashed { ", style=dashed" } else { "" };
+            writeln!(out, "  n{n} -> n{m} [label=\"{}\"{style}];", escape(&edge)).unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+// The label of an object and its outgoing edges: target, label and whether it goes to a captured variable.
+type Edges = Vec<(HeapPtr, String, bool)>;
+
+fn node(obj: &HeapObj) -> (String, Edges) {
+    let numbered = |ptrs: &[HeapPtr], dashed: bool| -> Edges {
+        ptrs.iter()
+            .enumerate()
+            .map(|(i, ptr)| (ptr.clone(), i.to_string(), dashed))
+            .collect()
+    };
+    match obj {
+        HeapObj::App(f, a) => (
+            "@".to_string(),
+            vec![
+                (f.clone(), "fun".to_string(), false),
+                (a.clone(), "arg".to_string(), false),
+            ],
+        ),
+        HeapObj::Value(value) => match value.ptr.target() {
+            Target::I32(n) => (n.to_string(), vec![]),
+            Target::Closure(closure) => {
+                let name = closure
+                    .code
+                    .combinator()
+                    .map_or("λ", |combinator| combinator.name);
+                (
+                    format!("{name}/{}", closure.arity),
+                    numbered(&closure.env, true),
+                )
+            }
+            Target::Con(ConObj { tag, fields }) => (tag.name.to_string(), numbered(fields, false)),
+            Target::Nullary(tag) => (tag.name.to_string(), vec![]),
+            Target::Var(level) => (crate::readback::name(level), vec![]),
+            // What the evaluator got stuck on, with an edge to the neutral term it got stuck at.
+            Target::Neutral(NeutralObj { head, elim }) => {
+                let mut edges = vec![(head.clone(), "head".to_string(), false)];
+                let label = match elim {
+                    Elim::App(arg) => {
+                        edges.push((arg.clone(), "arg".to_string(), false));
+                        "@"
+                    }
+                    Elim::Case(alts) => {
+                        edges.extend(alternatives(alts));
+                        "case"
+                    }
+                    Elim::Prim(op, args, i) => {
+                        let args = numbered(args, false).into_iter().enumerate();
+                        edges.extend(args.filter(|(j, _)| j != i).map(|(_, edge)| edge));
+                        op.name
+                    }
+                };
+                (format!("{label} (stuck)"), edges)
+            }
+            Target::Cell(_) | Target::Arena(_) => unreachable!("values are not in cells"),
+        },
+        HeapObj::Case(scrutinee, alts) => {
+            let mut edges = vec![(scrutinee.clone(), "of".to_string(), false)];
+            edges.extend(alternatives(alts));
+            ("case".to_string(), edges)
+        }
+        HeapObj::PrimApp(op, args) => (op.name.to_string(), numbered(args, false)),
+        // The arguments so far, and the captured variables of the closure.
+        HeapObj::Pap(closure, args) => {
+            let name = closure
+                .code
+                .combinator()
+                .map_or("λ", |combinator| combinator.name);
+            let mut edges = numbered(args, false);
+            edges.extend(numbered(&closure.env, true));
+            (
+                format!("{name}/{} {}", closure.arity, "·".repeat(args.len())),
+                edges,
+            )
+        }
+        HeapObj::BlackHole => ("blackhole".to_string(), vec![]),
+        HeapObj::Ind(target) => (
+            "ind".to_string(),
+            vec![(target.clone(), String::new(), false)],
+        ),
+    }
+}
+
+// Edges to the branches of a case, labeled with their constructors.
+fn alternatives(alts: &Alts) -> Edges {
+    let branches = alts
+        .branches
+        .iter()
+        .map(|(tag, branch)| (branch.clone(), tag.name.to_string(), false));
+    let default = alts
+        .default
+        .iter()
+        .map(|default| (default.clone(), "_".to_string(), false));
+    branches.chain(default).collect()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dot::{force_frames, to_dot};
+    use crate::{ap, con, i32, lambda, lambda_env, EvalStrategy, PAIR};
+
+    #[test]
+    fn shared_objects_are_drawn_once() {
+        // let five = 5 in Pair five five
+        let five = i32(5);
+        let pair = con(&PAIR, vec![five.clone(), five]);
+        let dot = to_dot(&pair);
+        assert_eq!(dot.matches("[label=\"5\"]").count(), 1);
+        assert!(dot.contains("n0 -> n1 [label=\"0\"]"));
+        assert!(dot.contains("n0 -> n1 [label=\"1\"]"));
+        // Captured variables are dashed.
+        let f = lambda_env(vec![pair], |env, _| env[0].clone());
+        assert!(to_dot(&f).contains("n0 -> n1 [label=\"0\", style=dashed]"));
+    }
+
+    #[test]
+    fn frames_of_evaluation() {
+        // (\x. x) 5
+        let t = ap(&lambda(|x| x), &i32(5));
+        let (frames, value) = force_frames(&t, EvalStrategy::Need);
+        assert_eq!(value.unwrap().i32(), Some(5));
+        // Entering the App, the lambda with the App blackholed, the result 5 before and after the update,
+        // and the heap after the evaluation.
+        assert_eq!(frames.len(), 5);
+        assert!(frames[0].contains("label=\"@\""));
+        assert!(frames[1].contains("label=\"blackhole\""));
+        assert!(frames[1].contains("n1 [style=filled, fillcolor=yellow]"));
+        // The App was overwritten with an indirection to the 5.
+        assert!(frames[4].contains("n0 [label=\"ind\"]"));
+        assert!(frames[4].contains("n1 [label=\"5\"]"));
+        assert!(!frames[3].contains("@") && !frames[3].contains("blackhole"));
+    }
+}
@@ -0,0 +1,423 @@
+// A tracing collector for the garbage reference counting can't free: cycles.
+//
+// Reference counting still frees most objects. The collector only looks for groups of objects which
+// keep each other alive, but are not reachable from the outside, e.g. a recursive thunk `x = f x` nobody points to.
+//
+// We don't know the roots (HeapPtrs in Rust variables, on the evaluator's stack, captured by Rust closures),
+// so we find them by "trial deletion": we count the references to each object coming from other heap objects.
+// An object with more strong references than that is referenced from the outside, so it is a root.
+// Everything reachable from the roots is alive. The rest is garbage: we clear these objects,
+// which breaks the cycles and lets Rc free them.
+//
+// HeapPtrs hidden inside Rust closures are not counted, so they look like outside references.
+// This keeps the collector safe, but cycles going through such closures are never collected.
+// Closures made with `lambda_env`, `lambda_n` or `comb` expose their captured variables and don't have this problem.
+//
+// Before that, pointers to indirections (HeapObj::Ind) are redirected to their targets,
+// so an indirection which only other heap objects pointed to is freed.
+//
+// Objects in a heap::Heap are not reference counted and not collected, they are freed with their Heap.
+// For the collector they are outside of the heap: reference counted objects they point to are roots.
+// So are the objects neutral terms (see NeutralObj) point to, which are not registered either:
+// they only come up when open terms are evaluated.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::{Closure, ClosureObj, ConObj, HeapCell, HeapObj, HeapPtr, Target};
+
+// All objects allocated on this thread: cells, closures, which point to other objects through their `env`,
+// and constructors. The collection starts when the number of them reaches `threshold`.
+struct Registry {
+    cells: Vec<Weak<HeapCell>>,
+    closures: Vec<Weak<ClosureObj>>,
+    cons: Vec<Weak<ConObj>>,
+    threshold: usize,
+}
+
+impl Registry {
+    fn len(&self) -> usize {
+        self.cells.len() + self.closures.len() + self.cons.len()
+    }
+
+    // Objects already freed by reference counting are dropped from the registry.
+    fn retain_alive(&mut self) {
+        self.cells.retain(|weak| weak.strong_count() > 0);
+        self.closures.retain(|weak| weak.strong_count() > 0);
+        self.cons.retain(|weak| weak.strong_count() > 0);
+    }
+}
+
+// Automatic collection happens no sooner than at this many registered objects.
+const MIN_THRESHOLD: usize = 1 << 16;
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = const {
+        RefCell::new(Registry {
+            cells: vec![],
+            closures: vec![],
+            cons: vec![],
+            threshold: MIN_THRESHOLD,
+        })
+    };
+}
+
+// Called by HeapPtr::new for every allocated cell.
+pub(crate) fn register(rc: &Rc<HeapCell>) {
+    add(|registry| registry.cells.push(Rc::downgrade(rc)))
+}
+
+// Called by Closure::new for every allocated closure.
+pub(crate) fn register_closure(closure: &Rc<ClosureObj>) {
+    add(|registry| registry.closures.push(Rc::downgrade(closure)))
+}
+
+// Called by `con` for every allocated constructor.
+pub(crate) fn register_con(con: &Rc<ConObj>) {
+    add(|registry| registry.cons.push(Rc::downgrade(con)))
+}
+
+fn add(push: impl FnOnce(&mut Registry)) {
+    let full = REGISTRY.with_borrow_mut(|registry| {
+        push(registry);
+        registry.len() >= registry.threshold
+    });
+    if full {
+        collect();
+        REGISTRY.with_borrow_mut(|registry| {
+            // Next automatic collection when the heap doubles.
+            registry.threshold = (2 * registry.len()).max(MIN_THRESHOLD);
+        });
+    }
+}
+
+// Number of objects allocated on this thread which are still alive.
+pub fn live_objects() -> usize {
+    REGISTRY.with_borrow(|registry| {
+        let cells = registry
+            .cells
+            .iter()
+            .filter(|weak| weak.strong_count() > 0)
+            .count();
+        let closures = registry
+            .closures
+            .iter()
+            .filter(|weak| weak.strong_count() > 0)
+            .count();
+        cells
+            + closures
+            + registry
+                .cons
+                .iter()
+                .filter(|weak| weak.strong_count() > 0)
+                .count()
+    })
+}
+
+// Frees all unreachable objects on this thread and returns how many were freed.
+pub fn collect() -> usize {
+    let objects: Vec<HeapPtr> = REGISTRY.with_borrow_mut(|registry| {
+        registry.retain_alive();
+        let cells = registry
+            .cells
+            .iter()
+            .filter_map(|weak| weak.upgrade().map(HeapPtr::cell));
+        let closures = registry.closures.iter().filter_map(|weak| weak.upgrade());
+        let closures = closures.map(|rc| HeapPtr::from(Closure::from_rc(rc)));
+        let cons = registry
+            .cons
+            .iter()
+            .filter_map(|weak| weak.upgrade().map(HeapPtr::con_obj));
+        cells.chain(closures).chain(cons).collect()
+    });
+    for ptr in &objects {
+        short_circuit(ptr);
+    }
+    let mut index: HashMap<Key, usize> = HashMap::with_capacity(objects.len());
+    index.extend(
+        objects
+            .iter()
+            .enumerate()
+            .filter_map(|(i, ptr)| Some((key(ptr)?, i))),
+    );
+    let slot = |key: &Key| index.get(key).copied();
+
+    // Count references from inside the heap. Case alternatives are behind an Rc, which may be shared by several
+    // objects. Their HeapPtrs count as inside the heap only if all references to the Rc do.
+    let mut inside = vec![0; objects.len()];
+    let mut shared: HashMap<Key, (usize, usize, Vec<Key>)> = HashMap::new();
+    for ptr in &objects {
+        let (direct, behind_rc) = edges(ptr);
+        for i in direct.iter().filter_map(slot) {
+            inside[i] += 1;
+        }
+        if let Some((key, strong, children)) = behind_rc {
+            shared.entry(key).or_insert((strong, 0, children)).1 += 1;
+        }
+    }
+    for (strong, holders, children) in shared.values() {
+        if strong == holders {
+            for i in children.iter().filter_map(slot) {
+                inside[i] += 1;
+            }
+        }
+    }
+
+    // Roots have references from the outside. `objects` itself holds one reference to each of them.
+    let mut stack: Vec<usize> = (0..objects.len())
+        .filter(|&i| {
+            objects[i]
+                .strong_count()
+                .expect("registered objects are counted")
+                - 1
+                > inside[i]
+        })
+        .collect();
+    let mut live = vec![false; objects.len()];
+    while let Some(i) = stack.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        let (direct, behind_rc) = edges(&objects[i]);
+        let behind_rc = behind_rc
+            .map(|(_, _, children)| children)
+            .unwrap_or_default();
+        stack.extend(direct.iter().chain(&behind_rc).filter_map(slot));
+    }
+
+    // Clear the garbage. The old contents are dropped only after all garbage is cleared,
+    // so dropping them never recurses into another garbage object.
+    // Closures and constructors can't be cleared, but every cycle goes through a cell: they only point to objects
+    // which existed before them. Once the cells are cleared, the values are freed by Rc.
+    let mut garbage: Vec<HeapObj> = vec![];
+    let mut freed = 0;
+    for (ptr, live) in objects.iter().zip(live) {
+        if !live {
+            freed += 1;
+            if ptr.rc().is_some() {
+                garbage.push(std::mem::replace(obj_mut(ptr), HeapObj::BlackHole));
+            }
+        }
+    }
+    drop(garbage);
+    drop(objects);
+    REGISTRY.with_borrow_mut(Registry::retain_alive);
+    freed
+}
+
+// Registered objects are told apart by their address.
+type Key = *const ();
+
+// Objects in a Heap are not registered, they are outside. Unboxed values are not objects at all.
+fn key(ptr: &HeapPtr) -> Option<Key> {
+    match ptr.target() {
+        Target::Cell(cell) => Some(cell as *const HeapCell as Key),
+        Target::Closure(closure) => Some(closure_key(closure)),
+        Target::Con(con) => Some(con as *const ConObj as Key),
+        Target::Arena(_)
+        | Target::Nullary(_)
+        | Target::I32(_)
+        | Target::Var(_)
+        | Target::Neutral(_) => None,
+    }
+}
+
+fn closure_key(closure: &ClosureObj) -> Key {
+    closure as *const ClosureObj as Key
+}
+
+// Objects the object points to: directly, and through an Rc (its address, strong count and the objects).
+type Edges = (Vec<Key>, Option<(Key, usize, Vec<Key>)>);
+
+fn edges(ptr: &HeapPtr) -> Edges {
+    let obj = match ptr.target() {
+        Target::Closure(closure) => return (closure.env.iter().filter_map(key).collect(), None),
+        Target::Con(con) => return (con.fields.iter().filter_map(key).collect(), None),
+        _ => obj(ptr),
+    };
+    let mut direct: Vec<Key> = vec![];
+    each_field(obj, |field| direct.extend(key(field)));
+    match obj {
+        HeapObj::Pap(closure, _) => direct.push(closure_key(closure)),
+        HeapObj::Case(_, alts) => {
+            let branches = alts
+                .branches
+                .iter()
+                .map(|(_, branch)| branch)
+                .chain(&alts.default);
+            let branches = branches.filter_map(key).collect();
+            return (
+                direct,
+                Some((Rc::as_ptr(alts) as Key, Rc::strong_count(alts), branches)),
+            );
+        }
+        _ => {}
+    }
+    (direct, None)
+}
+
+// Calls `f` with the HeapPtrs in the object itself, not behind an Rc.
+fn each_field<'a>(obj: &'a HeapObj, mut f: impl FnMut(&'a HeapPtr)) {
+    match obj {
+        HeapObj::App(fun, arg) => {
+            f(fun);
+            f(arg);
+        }
+        HeapObj::Case(ptr, _) | HeapObj::Ind(ptr) => f(ptr),
+        HeapObj::Value(value) => f(&value.ptr),
+        HeapObj::PrimApp(_, fields) | HeapObj::Pap(_, fields) => {
+            for field in fields.iter() {
+                f(field);
+            }
+        }
+        HeapObj::BlackHole => {}
+    }
+}
+
+// Points the fields of a cell past indirections. Closure environments, constructor fields and case alternatives
+// are shared behind an Rc, so we leave them be.
+fn short_circuit(ptr: &HeapPtr) {
+    if ptr.rc().is_none() {
+        return;
+    }
+    let mut any_ind = false;
+    each_field(obj(ptr), |field| any_ind |= is_ind(field));
+    if !any_ind {
+        return;
+    }
+    let mut copy = ptr.raw();
+    let children: Vec<&mut HeapPtr> = match &mut copy {
+        HeapObj::App(f, a) => vec![f, a],
+        HeapObj::Case(scrutinee, _) => vec![scrutinee],
+        HeapObj::PrimApp(_, fields) | HeapObj::Pap(_, fields) => fields.iter_mut().collect(),
+        HeapObj::Ind(target) => vec![target],
+        HeapObj::Value(_) | HeapObj::BlackHole => vec![],
+    };
+    for child in children {
+        while is_ind(child) {
+            let HeapObj::Ind(target) = obj(child) else {
+                unreachable!()
+            };
+            *child = target.clone();
+        }
+    }
+    ptr.set(copy);
+}
+
+// Objects in a Heap may not be accessible now, so only reference counted indirections are removed.
+fn is_ind(ptr: &HeapPtr) -> bool {
+    ptr.rc().is_some() && matches!(obj(ptr), HeapObj::Ind(_))
+}
+
+// The collector reads objects in place: cloning them would change the reference counts it looks at.
+fn obj(ptr: &HeapPtr) -> &HeapObj {
+    // safety: Nothing mutates heap objects while the collector runs (it runs inside HeapPtr::new or on its own).
+    unsafe { &*ptr.rc().expect("a cell").obj.get() }
+}
+
+#[allow(clippy::mut_from_ref)]
+fn obj_mut(ptr: &HeapPtr) -> &mut HeapObj {
+    // safety: Only called on garbage objects, which nobody outside of the collector can reach.
+    unsafe { &mut *ptr.rc().expect("a cell").obj.get() }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gc::collect;
+    use crate::{
+        ap, con, i32, lambda, lambda_env, lambda_n, letrec, HeapCell, HeapObj, HeapPtr, CONS, JUST,
+    };
+    use std::rc::Rc;
+
+    fn is_freed(weak: &std::rc::Weak<HeapCell>) -> bool {
+        weak.strong_count() == 0
+    }
+
+    #[test]
+    fn collects_cycles_of_thunks() {
+        // x = id x, unreachable once we drop x.
+        let id = lambda(|x| x);
+        let x = HeapPtr::new(HeapObj::BlackHole);
+        x.set(HeapObj::App(id.clone(), x.clone()));
+        let weak = Rc::downgrade(&x.rc().unwrap());
+        drop(x);
+        assert!(!is_freed(&weak));
+        collect();
+        assert!(is_freed(&weak));
+        // id is referenced from the outside, so it survives.
+        assert!(matches!(id.get(), HeapObj::Value(_)));
+    }
+
+    #[test]
+    fn collects_cycles_through_closure_environments() {
+        // f = \y. f, the closure captures f itself in its env.
+        let f = HeapPtr::new(HeapObj::BlackHole);
+        let closure = lambda_env(vec![f.clone()], |env, _| env[0].clone());
+        f.set(closure.get());
+        drop(closure);
+        let t = ap(&f, &i32(1));
+        let weak = Rc::downgrade(&f.rc().unwrap());
+        drop(f);
+        collect();
+        // Still reachable from t.
+        assert!(!is_freed(&weak));
+        t.force();
+        assert!(matches!(t.get(), HeapObj::Value(_)));
+        drop(t);
+        collect();
+        assert!(is_freed(&weak));
+    }
+
+    #[test]
+    fn collects_cyclic_constructors() {
+        // ones = Cons 1 ones
+        let ones = letrec(|ones| con(&CONS, vec![i32(1), ones.clone()]));
+        let weak = Rc::downgrade(&ones.rc().unwrap());
+        drop(ones);
+        collect();
+        assert!(is_freed(&weak));
+    }
+
+    #[test]
+    fn collects_cyclic_partial_applications() {
+        // x = f x where f = \a b. a, so x evaluates to the partial application of f to x.
+        let f = lambda_n(vec![], 2, |_, args| args[0].clone());
+        let x = letrec(|x| ap(&f, x));
+        x.force();
+        assert!(matches!(x.get(), HeapObj::Pap(_, _)));
+        let weak = Rc::downgrade(&x.rc().unwrap());
+        drop(x);
+        collect();
+        assert!(is_freed(&weak));
+    }
+
+    #[test]
+    fn removes_indirections() {
+        // let t = id (Just 5) in t t
+        let just = con(&JUST, vec![i32(5)]);
+        let t = ap(&lambda(|x| x), &just);
+        let u = ap(&t, &t);
+        t.force();
+        assert!(matches!(t.raw(), HeapObj::Ind(_)));
+        let weak = Rc::downgrade(&t.rc().unwrap());
+        drop(t);
+        collect();
+        // The App points to the Just directly, nothing points to the indirection anymore.
+        assert!(is_freed(&weak));
+        let HeapObj::App(f, a) = u.get() else {
+            panic!("not an App")
+        };
+        assert!(f.ptr_eq(&just) && a.ptr_eq(&just));
+    }
+
+    #[test]
+    fn keeps_everything_reachable() {
+        let x = HeapPtr::new(HeapObj::BlackHole);
+        x.set(HeapObj::App(lambda(|_| i32(7)), x.clone()));
+        let y: HeapPtr = ap(&lambda(|x| x), &x);
+        collect();
+        y.force();
+        assert_eq!(x.value().unwrap().i32(), Some(7));
+    }
+}
@@ -0,0 +1,225 @@
+// An arena for heap objects: they live next to each other in a Vec and HeapPtrs into it are indices.
+//
+//   let mut heap = Heap::new();
+//   let five = heap.run(|| ap(&lambda(|x| x), &i32(5)).try_i32());
+//
+// While `run` runs, HeapPtr::new allocates in the heap, so `lambda`, `ap`, `i32`, the parser and the evaluator
+// work unchanged. Outside of it objects are reference counted as before.
+//
+// Objects in the heap are not freed one by one, there is no reference counting: all of them are freed at once,
+// when the Heap is dropped. The objects are in a contiguous vector, which is friendlier to the cache than
+// separate allocations, and which a copying collector could compact some day.
+//
+// A HeapPtr into the heap is the id of the heap and the index of the object. It can outlive `run`, and even the heap,
+// but it can be used only in `run` of its heap, elsewhere it panics instead of pointing to garbage.
+// Objects in the heap may point to reference counted ones and the other way around.
+// Closures and constructors are reference counted also inside of `run`, they don't live in cells (see HeapPtr in lib.rs).
+use std::cell::{Cell, RefCell};
+
+use crate::{stats, HeapObj, TAG_ARENA};
+
+pub struct Heap {
+    arena: Arena,
+}
+
+struct Arena {
+    id: u32,
+    objects: Vec<HeapObj>,
+    // Which objects were allocated as thunks, see HeapPtr::was_thunk.
+    thunks: Vec<bool>,
+}
+
+// A HeapPtr into a Heap.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Index {
+    heap: u32,
+    slot: u32,
+}
+
+impl Index {
+    // The word of a HeapPtr, with the tag in the lowest 3 bits (see HeapPtr in lib.rs).
+    // There are less than 2^29 heaps, so the heap and the slot fit in the other 61 bits.
+    pub(crate) fn pack(self) -> usize {
+        (self.heap as usize) << 35 | (self.slot as usize) << 3 | TAG_ARENA
+    }
+
+    pub(crate) fn unpack(word: usize) -> Index {
+        Index {
+            heap: (word >> 35) as u32,
+            slot: (word >> 3) as u32,
+        }
+    }
+}
+
+thread_local! {
+    // The heap of the innermost `run`, its objects are moved here for the time being.
+    static CURRENT: RefCell<Option<Arena>> = const { RefCell::new(None) };
+    static NEXT_ID: Cell<u32> = const { Cell::new(0) };
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        let id = NEXT_ID.get();
+        assert!(id < 1 << 29, "too many heaps");
+        NEXT_ID.set(id + 1);
+        Heap {
+            arena: Arena {
+                id,
+                objects: vec![],
+                thunks: vec![],
+            },
+        }
+    }
+
+    // Runs `f` with new objects allocated in this heap. Runs can be nested, the innermost heap gets the objects.
+    pub fn run<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        // The objects are put back even if `f` panics.
+        struct Restore<'a> {
+            heap: &'a mut Heap,
+            outer: Option<Arena>,
+        }
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                let arena = CURRENT.replace(self.outer.take());
+                self.heap.arena = arena.expect("the heap is still running");
+            }
+        }
+        let empty = Arena {
+            id: self.arena.id,
+            objects: vec![],
+            thunks: vec![],
+        };
+        let arena = std::mem::replace(&mut self.arena, empty);
+        let outer = CURRENT.replace(Some(arena));
+        let _restore = Restore { heap: self, outer };
+        f()
+    }
+
+    // Number of objects allocated in this heap.
+    pub fn len(&self) -> usize {
+        self.arena.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arena.objects.is_empty()
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for _ in &self.arena.objects {
+            stats::freed();
+        }
+    }
+}
+
+// Allocates in the heap of the current `run`, if there is one.
+pub(crate) fn alloc(obj: HeapObj, thunk: bool) -> Result<Index, HeapObj> {
+    CURRENT.with_borrow_mut(|current| match current {
+        Some(arena) => {
+            let slot = u32::try_from(arena.objects.len()).expect("heap is full");
+            arena.objects.push(obj);
+            arena.thunks.push(thunk);
+            Ok(Index {
+                heap: arena.id,
+                slot,
+            })
+        }
+        None => Err(obj),
+    })
+}
+
+// Gives `f` the object at `index`. The heap is borrowed while `f` runs, so `f` must not touch any other HeapPtr
+// of the heap (cloning them is fine) and the old object replaced by `f` should be dropped after it returns.
+pub(crate) fn with<R>(index: Index, f: impl FnOnce(&mut HeapObj) -> R) -> R {
+    CURRENT.with_borrow_mut(|current| match current {
+        Some(arena) if arena.id == index.heap => f(&mut arena.objects[index.slot as usize]),
+        _ => panic!("a HeapPtr into a Heap used outside of Heap::run"),
+    })
+}
+
+pub(crate) fn is_thunk(index: Index) -> bool {
+    CURRENT.with_borrow(|current| match current {
+        Some(arena) if arena.id == index.heap => arena.thunks[index.slot as usize],
+        _ => panic!("a HeapPtr into a Heap used outside of Heap::run"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::heap::Heap;
+    use crate::{ap, i32, lambda, lambda_n, letrec, parser, stats, HeapObj};
+
+    #[test]
+    fn helpers_allocate_in_the_heap() {
+        let mut heap = Heap::new();
+        let five = heap.run(|| {
+            // (\x. x) 5
+            let t = ap(&lambda(|x| x), &i32(5));
+            t.try_i32().unwrap()
+        });
+        assert_eq!(five, 5);
+        // The App, which was updated in place. The lambda is a closure and the 5 is unboxed, they are not in a cell.
+        assert_eq!(heap.len(), 1);
+        // Objects outside of `run` are reference counted as before.
+        assert_eq!(ap(&lambda(|x| x), &i32(6)).try_i32().unwrap(), 6);
+        assert_eq!(heap.len(), 1);
+    }
+
+    #[test]
+    fn freed_with_the_heap() {
+        let live = stats::get().live;
+        let mut heap = Heap::new();
+        // ones = Cons 1 ones, reference counting alone would never free it.
+        let ones = heap.run(|| letrec(|ones| crate::con(&crate::CONS, vec![i32(1), ones.clone()])));
+        // The cell of `ones` is in the heap, the constructor in it is reference counted.
+        assert_eq!(stats::get().live, live + heap.len() + 1);
+        drop(heap);
+        assert_eq!(stats::get().live, live);
+        drop(ones);
+    }
+
+    #[test]
+    fn programs_run_in_the_heap() {
+        let src = "letrec length xs = case xs of { Nil -> 0; Cons h t -> 1 + length t } in
+                   letrec range a b = if a > b then Nil else Cons a (range (a + 1) b) in
+                   length (range 1 100)";
+        let mut heap = Heap::new();
+        assert_eq!(
+            heap.run(|| parser::parse(src).unwrap().try_i32().unwrap()),
+            100
+        );
+        assert!(heap.len() > 100);
+    }
+
+    #[test]
+    fn mixed_with_reference_counted_objects() {
+        let id = lambda(|x| x);
+        let mut heap = Heap::new();
+        let t = heap.run(|| ap(&id, &i32(5)));
+        // A reference counted thunk of an object in the heap.
+        let u = ap(&id, &t);
+        assert_eq!(heap.run(|| u.try_i32().unwrap()), 5);
+        // k 5 is evaluated in place to a partial application, in the heap.
+        let k = lambda_n(vec![], 2, |_, args| args[0].clone());
+        let t = heap.run(|| ap(&k, &i32(5)));
+        let u = ap(&id, &t);
+        heap.run(|| u.force());
+        // `u` was updated with an indirection into the heap, so it can be looked at only in `run`.
+        assert!(heap.run(|| matches!(u.get(), HeapObj::Pap(_, _))));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of Heap::run")]
+    fn used_outside_of_run() {
+        let mut heap = Heap::new();
+        let t = heap.run(|| ap(&lambda(|x| x), &i32(5)));
+        t.force();
+    }
+}
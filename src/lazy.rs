@@ -0,0 +1,315 @@
+// HeapPtrs with the Rust type of what they evaluate to, for host code which calls into the runtime.
+//
+//   let inc = Lazy::<fn(i32) -> i32>::from_term(&parse_term("\\x. x + 1")?)?;
+//   inc.apply(&Lazy::new(41)).force()? == 42
+//
+// A HeapPtr can be anything, so every result has to be taken apart by hand: `ptr.try_force()?.i32()` and
+// a match on None, `con()` and a look at the tag. A Lazy<T> is a HeapPtr which is known to evaluate to a T,
+// `force` returns the T itself. `T` is only a marker, nothing of type T is stored, and Lazy<fn(A) -> B> is a
+// function: `apply` takes a Lazy<A>, so applying it to anything else doesn't compile.
+//
+// Types are in Rust and in types.rs: i32 is Int, bool is Bool, () is Unit, (A, B) is Pair a b,
+// Option<A> is Maybe a, Vec<A> is List a and fn(A) -> B is a -> b. `from_term` type checks the term before
+// it compiles it, so a Lazy made that way evaluates to a T if it evaluates at all: forcing it can still fail,
+// like any evaluation. It may loop, hit a case with no matching alternative or overflow. `assume` checks nothing,
+// a Lazy made with it is only as good as the caller's word.
+use std::marker::PhantomData;
+
+use crate::parser::{Env, Term};
+use crate::types::{self, Type, TypeEnv, TypeError};
+use crate::{ap, con, EvalError, HeapPtr, CONS, FALSE, JUST, NIL, NOTHING, PAIR, TRUE, UNIT};
+
+pub struct Lazy<T> {
+    ptr: HeapPtr,
+    // fn() -> T rather than T: a Lazy<T> doesn't own a T, dropping it never drops one.
+    ty: PhantomData<fn() -> T>,
+}
+
+// Rust types with a type in types.rs.
+pub trait Typed {
+    fn ty() -> Type;
+}
+
+// Types whose values are moved between Rust and the heap. Functions are not: a closure can't be looked into.
+pub trait Data: Typed + Sized {
+    fn to_heap(&self) -> HeapPtr;
+    // Forces `ptr`, and everything in it: Vec<i32> is the whole list.
+    fn from_heap(ptr: &HeapPtr) -> Result<Self, EvalError>;
+}
+
+impl<T> Lazy<T> {
+    // Trusts that `ptr` evaluates to a T. If it doesn't, `force` fails with EvalError::TypeMismatch, but a
+    // function may be applied to anything, and how it goes wrong is up to its code.
+    pub fn assume(ptr: HeapPtr) -> Lazy<T> {
+        Lazy {
+            ptr,
+            ty: PhantomData,
+        }
+    }
+
+    pub fn ptr(&self) -> &HeapPtr {
+        &self.ptr
+    }
+
+    pub fn into_ptr(self) -> HeapPtr {
+        self.ptr
+    }
+}
+
+impl<T: Typed> Lazy<T> {
+    // Compiles a closed term, if it has the type T. A polymorphic term is fine: `\x. x` is a fn(i32) -> i32.
+    pub fn from_term(term: &Term) -> Result<Lazy<T>, TypeError> {
+        types::check(term, &TypeEnv::default(), &T::ty())?;
+        let ptr = term
+            .compile(&Env::default())
+            .expect("well typed terms have no unbound variables");
+        Ok(Lazy::assume(ptr))
+    }
+}
+
+impl<T: Data> Lazy<T> {
+    pub fn new(value: T) -> Lazy<T> {
+        Lazy::assume(value.to_heap())
+    }
+
+    pub fn force(&self) -> Result<T, EvalError> {
+        T::from_heap(&self.ptr)
+    }
+}
+
+impl<A, B> Lazy<fn(A) -> B> {
+    pub fn lambda(f: impl Fn(Lazy<A>) -> Lazy<B> + 'static) -> Lazy<fn(A) -> B> {
+        Lazy::assume(crate::lambda(move |x| f(Lazy::assume(x)).ptr))
+    }
+
+    // A thunk, nothing is evaluated yet.
+    pub fn apply(&self, arg: &Lazy<A>) -> Lazy<B> {
+        Lazy::assume(ap(&self.ptr, &arg.ptr))
+    }
+}
+
+// Not derived, which would want T: Clone.
+impl<T> Clone for Lazy<T> {
+    fn clone(&self) -> Self {
+        Lazy::assume(self.ptr.clone())
+    }
+}
+
+impl Typed for i32 {
+    fn ty() -> Type {
+        types::int()
+    }
+}
+
+impl Typed for bool {
+    fn ty() -> Type {
+        Type::Con("Bool", vec![])
+    }
+}
+
+impl Typed for () {
+    fn ty() -> Type {
+        Type::Con("Unit", vec![])
+    }
+}
+
+impl<A: Typed, B: Typed> Typed for (A, B) {
+    fn ty() -> Type {
+        Type::Con("Pair", vec![A::ty(), B::ty()])
+    }
+}
+
+impl<A: Typed> Typed for Option<A> {
+    fn ty() -> Type {
+        Type::Con("Maybe", vec![A::ty()])
+    }
+}
+
+impl<A: Typed> Typed for Vec<A> {
+    fn ty() -> Type {
+        Type::Con("List", vec![A::ty()])
+    }
+}
+
+impl<A: Typed, B: Typed> Typed for fn(A) -> B {
+    fn ty() -> Type {
+        types::fun(A::ty(), B::ty())
+    }
+}
+
+fn mismatch(expected: &'static str, found: &HeapPtr) -> EvalError {
+    EvalError::TypeMismatch {
+        expected,
+        found: found.clone(),
+    }
+}
+
+impl Data for i32 {
+    fn to_heap(&self) -> HeapPtr {
+        crate::i32(*self)
+    }
+
+    fn from_heap(ptr: &HeapPtr) -> Result<i32, EvalError> {
+        ptr.try_i32()
+    }
+}
+
+impl Data for bool {
+    fn to_heap(&self) -> HeapPtr {
+        crate::bool(*self)
+    }
+
+    fn from_heap(ptr: &HeapPtr) -> Result<bool, EvalError> {
+        match ptr.try_force()?.con() {
+            Some((tag, _)) if tag == &TRUE => Ok(true),
+            Some((tag, _)) if tag == &FALSE => Ok(false),
+            _ => Err(mismatch("Bool", ptr)),
+        }
+    }
+}
+
+impl Data for () {
+    fn to_heap(&self) -> HeapPtr {
+        con(&UNIT, vec![])
+    }
+
+    fn from_heap(ptr: &HeapPtr) -> Result<(), EvalError> {
+        match ptr.try_force()?.con() {
+            Some((tag, _)) if tag == &UNIT => Ok(()),
+            _ => Err(mismatch("Unit", ptr)),
+        }
+    }
+}
+
+impl<A: Data, B: Data> Data for (A, B) {
+    fn to_heap(&self) -> HeapPtr {
+        con(&PAIR, vec![self.0.to_heap(), self.1.to_heap()])
+    }
+
+    fn from_heap(ptr: &HeapPtr) -> Result<(A, B), EvalError> {
+        match ptr.try_force()?.con() {
+            Some((tag, fields)) if tag == &PAIR => {
+                Ok((A::from_heap(&fields[0])?, B::from_heap(&fields[1])?))
+            }
+            _ => Err(mismatch("Pair", ptr)),
+        }
+    }
+}
+
+impl<A: Data> Data for Option<A> {
+    fn to_heap(&self) -> HeapPtr {
+        match self {
+            Some(a) => con(&JUST, vec![a.to_heap()]),
+            None => con(&NOTHING, vec![]),
+        }
+    }
+
+    fn from_heap(ptr: &HeapPtr) -> Result<Option<A>, EvalError> {
+        match ptr.try_force()?.con() {
+            Some((tag, fields)) if tag == &JUST => Ok(Some(A::from_heap(&fields[0])?)),
+            Some((tag, _)) if tag == &NOTHING => Ok(None),
+            _ => Err(mismatch("Maybe", ptr)),
+        }
+    }
+}
+
+impl<A: Data> Data for Vec<A> {
+    // Cons cells are made from the end.
+    fn to_heap(&self) -> HeapPtr {
+        self.iter().rev().fold(con(&NIL, vec![]), |list, a| {
+            con(&CONS, vec![a.to_heap(), list])
+        })
+    }
+
+    // A loop along the tail, long lists don't take stack. A cyclic or infinite list is read until memory runs out.
+    fn from_heap(ptr: &HeapPtr) -> Result<Vec<A>, EvalError> {
+        let mut items = vec![];
+        let mut list = ptr.clone();
+        loop {
+            match list.try_force()?.con() {
+                Some((tag, _)) if tag == &NIL => return Ok(items),
+                Some((tag, fields)) if tag == &CONS => {
+                    items.push(A::from_heap(&fields[0])?);
+                    list = fields[1].clone();
+                }
+                _ => return Err(mismatch("List", &list)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lazy::Lazy;
+    use crate::parser::parse_term;
+    use crate::types::TypeError;
+    use crate::{i32, EvalError};
+
+    fn from_src<T: crate::lazy::Typed>(src: &str) -> Result<Lazy<T>, TypeError> {
+        Lazy::from_term(&parse_term(src).unwrap())
+    }
+
+    #[test]
+    fn typed_application() {
+        let add = from_src::<fn(i32) -> fn(i32) -> i32>("\\a b. a + b").unwrap();
+        assert_eq!(
+            add.apply(&Lazy::new(40))
+                .apply(&Lazy::new(2))
+                .force()
+                .unwrap(),
+            42
+        );
+        // Polymorphic terms get the type they are asked for.
+        let id = from_src::<fn(Vec<bool>) -> Vec<bool>>("\\x. x").unwrap();
+        assert_eq!(
+            id.apply(&Lazy::new(vec![true, false])).force().unwrap(),
+            vec![true, false]
+        );
+        // Rust closures are functions too.
+        let twice = Lazy::<fn(i32) -> i32>::lambda(move |x| add.apply(&x).apply(&x));
+        assert_eq!(twice.apply(&Lazy::new(21)).force().unwrap(), 42);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = (vec![Some(1), None, Some(-3)], (true, ()));
+        assert_eq!(Lazy::new(value.clone()).force().unwrap(), value);
+        let src = "letrec range a b = if a > b then Nil else Cons (Pair a (a * a)) (range (a + 1) b) in range 1 3";
+        let squares = from_src::<Vec<(i32, i32)>>(src).unwrap();
+        assert_eq!(squares.force().unwrap(), vec![(1, 1), (2, 4), (3, 9)]);
+        // Long lists are read in a loop.
+        let ones = from_src::<Vec<i32>>(
+            "letrec f n = if n == 0 then Nil else Cons 1 (f (n - 1)) in f 100000",
+        )
+        .unwrap();
+        assert_eq!(ones.force().unwrap().len(), 100000);
+    }
+
+    #[test]
+    fn checked_against_the_type() {
+        assert!(matches!(
+            from_src::<i32>("\\x. x"),
+            Err(TypeError::Mismatch { .. })
+        ));
+        assert!(matches!(
+            from_src::<fn(bool) -> i32>("\\x. x + 1"),
+            Err(TypeError::Mismatch { .. })
+        ));
+        assert!(matches!(from_src::<i32>("y"), Err(TypeError::Unbound(_))));
+        // Well typed terms can still fail when forced.
+        let div = from_src::<i32>("div 1 0").unwrap();
+        assert!(matches!(div.force(), Err(EvalError::Arithmetic(_))));
+        // A default alone matches a value of any type, the checker and the evaluator agree on it.
+        let five = from_src::<i32>("case 5 of { x -> x }").unwrap();
+        assert_eq!(five.force().unwrap(), 5);
+        // Unchecked ones only when looked at.
+        let wrong = Lazy::<bool>::assume(i32(1));
+        assert!(matches!(
+            wrong.force(),
+            Err(EvalError::TypeMismatch {
+                expected: "Bool",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,1987 @@
+#![allow(unused_variables)]
+// The runtime lives in the library; src/main.rs is a REPL on top of it.
+// Reference counting is our GC replacement. A tracing collector takes care of the cycles.
+use std::rc::Rc;
+pub mod gc;
+
+// We use UnsafeCell to mutate heap objects in-place when forcing lambda evaluation.
+use std::cell::{RefCell, UnsafeCell};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+
+use std::fmt;
+
+// Textual syntax on top of the HOAS helpers below.
+pub mod parser;
+
+// Arithmetic and comparisons.
+pub mod primops;
+use primops::PrimOp;
+
+// `lam!`, less verbose `lambda`.
+mod macros;
+
+// Counters for benchmarks.
+pub mod stats;
+
+// Drawing the heap with Graphviz.
+pub mod dot;
+
+// An arena to allocate in instead of reference counting.
+pub mod heap;
+
+// Printing closures as lambda terms.
+pub mod readback;
+
+// Type inference for parsed terms.
+pub mod types;
+
+// Typed handles on HeapPtrs for Rust code.
+pub mod lazy;
+
+// Value makes it easier to add more types to the calculus.
+// Right now we have Closures, i32 and constructors of algebraic data types.
+// If our calculus was typed, we could use union instead of enum, since we would always know which enum case it is.
+// Parsed terms can be type checked (see types.rs), but HOAS terms can't, so the runtime doesn't rely on types.
+// It is not, but we don't need the discriminant of an enum either: a Value is a HeapPtr to a closure,
+// a constructor or an unboxed i32, and the tag bits of the HeapPtr tell which one it is (see HeapPtr).
+// So a Value is a single word.
+#[derive(Clone)]
+pub struct Value {
+    ptr: HeapPtr,
+}
+
+// A saturated constructor. The fields are not evaluated, constructors are lazy.
+// Like closures, constructors are never overwritten, so they are not in a cell: a HeapPtr points straight to them.
+pub(crate) struct ConObj {
+    pub(crate) tag: Tag,
+    pub(crate) fields: Box<[HeapPtr]>,
+}
+
+impl Drop for ConObj {
+    fn drop(&mut self) {
+        stats::freed();
+    }
+}
+
+// A neutral term: a free variable (see `var`) applied to arguments, scrutinized by a case or passed to a primop.
+// Evaluation can't go on without knowing the variable, so the neutral term is a value which remembers what was to be
+// done with it: the spine. Each NeutralObj is the neutral `head` and one more eliminator, a free variable alone is
+// unboxed like a constructor without fields.
+pub(crate) struct NeutralObj {
+    pub(crate) head: HeapPtr,
+    pub(crate) elim: Elim,
+}
+
+// What the evaluator got stuck on, the frame it had on its stack for the value.
+#[derive(Clone)]
+pub enum Elim {
+    App(HeapPtr),
+    Case(Rc<Alts>),
+    // The neutral term is the i-th argument.
+    Prim(&'static PrimOp, Box<[HeapPtr]>, usize),
+}
+
+impl Drop for NeutralObj {
+    fn drop(&mut self) {
+        stats::freed();
+    }
+}
+
+// Constructors are described by static tables, a bit like GHC's info tables.
+// The tag of a constructor value is a pointer to its table.
+#[derive(Debug)]
+pub struct Constructor {
+    pub name: &'static str,
+    pub arity: usize,
+}
+
+pub type Tag = &'static Constructor;
+
+// Tags are equal when they point to the same table.
+impl PartialEq for Constructor {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Some data types are common enough to be predefined.
+pub static FALSE: Constructor = Constructor {
+    name: "False",
+    arity: 0,
+};
+pub static TRUE: Constructor = Constructor {
+    name: "True",
+    arity: 0,
+};
+pub static NIL: Constructor = Constructor {
+    name: "Nil",
+    arity: 0,
+};
+pub static CONS: Constructor = Constructor {
+    name: "Cons",
+    arity: 2,
+};
+pub static PAIR: Constructor = Constructor {
+    name: "Pair",
+    arity: 2,
+};
+pub static NOTHING: Constructor = Constructor {
+    name: "Nothing",
+    arity: 0,
+};
+pub static JUST: Constructor = Constructor {
+    name: "Just",
+    arity: 1,
+};
+pub static UNIT: Constructor = Constructor {
+    name: "Unit",
+    arity: 0,
+};
+
+pub static CONSTRUCTORS: [Tag; 8] = [&FALSE, &TRUE, &NIL, &CONS, &PAIR, &NOTHING, &JUST, &UNIT];
+
+// This are just some accesseors that make the code less messy.
+// They are safe, unlike the fields of a union: they look at the tag before reading the word.
+impl Value {
+    pub fn i32(self: Value) -> Option<i32> {
+        if let Target::I32(i) = self.ptr.target() {
+            return Some(i);
+        }
+        None
+    }
+
+    pub fn closure(self: Value) -> Option<Closure> {
+        self.ptr.closure()
+    }
+
+    pub fn con(self: Value) -> Option<(Tag, Vec<HeapPtr>)> {
+        self.as_con().map(|(tag, fields)| (tag, fields.to_vec()))
+    }
+
+    // The free variable and what was done to it, in order.
+    pub fn neutral(self) -> Option<(u32, Vec<Elim>)> {
+        let mut spine = vec![];
+        let mut ptr = self.ptr;
+        loop {
+            match ptr.target() {
+                Target::Var(x) => {
+                    spine.reverse();
+                    return Some((x, spine));
+                }
+                Target::Neutral(neutral) => {
+                    spine.push(neutral.elim.clone());
+                    ptr = neutral.head.clone();
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn is_neutral(&self) -> bool {
+        matches!(self.ptr.tag(), TAG_VAR | TAG_NEUTRAL)
+    }
+
+    // Same, without copying the fields.
+    fn as_con(&self) -> Option<(Tag, &[HeapPtr])> {
+        match self.ptr.target() {
+            Target::Con(con) => Some((con.tag, &con.fields)),
+            Target::Nullary(tag) => Some((tag, &[])),
+            _ => None,
+        }
+    }
+}
+
+// Values are what the REPL prints. Closures are opaque Rust code, so we can't show their bodies without calling them
+// (see readback.rs). At least combinators have names.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ptr.target() {
+            Target::I32(i) => write!(f, "{i}"),
+            Target::Closure(closure) => match closure.code.combinator() {
+                Some(combinator) => write!(f, "<{}>", combinator.name),
+                None => write!(f, "<closure>"),
+            },
+            // Only weak head normal form is guaranteed, so fields are printed shallowly.
+            Target::Con(ConObj { tag, fields }) => {
+                write!(f, "{}", tag.name)?;
+                fields.iter().try_for_each(|field| write!(f, " {field:?}"))
+            }
+            Target::Nullary(tag) => write!(f, "{}", tag.name),
+            Target::Var(level) => write!(f, "{}", readback::name(level)),
+            // Also shallow, like constructors. Primops are printed as functions, e.g. `(+) x 1`.
+            Target::Neutral(NeutralObj { head, elim }) => {
+                let applied = matches!(head.target(), Target::Var(_))
+                    || matches!(
+                        head.target(),
+                        Target::Neutral(NeutralObj {
+                            elim: Elim::App(_),
+                            ..
+                        })
+                    );
+                let head = head.value().expect("neutral");
+                match elim {
+                    Elim::App(arg) if applied => write!(f, "{head} {arg:?}"),
+                    Elim::App(arg) => write!(f, "({head}) {arg:?}"),
+                    Elim::Case(_) => write!(f, "case {head} of {{..}}"),
+                    Elim::Prim(op, args, i) => {
+                        write!(f, "({})", op.name)?;
+                        for (j, arg) in args.iter().enumerate() {
+                            match j == *i {
+                                false => write!(f, " {arg:?}")?,
+                                true if head.ptr.tag() == TAG_VAR => write!(f, " {head}")?,
+                                true => write!(f, " ({head})")?,
+                            }
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            Target::Cell(_) | Target::Arena(_) => unreachable!("values are not in cells"),
+        }
+    }
+}
+
+// HeapObj represents unevaluead (App) or evaluated lambda calculus terms.
+// When in heap memory, HeapObj will be in UnsafeCell and can be mutated in place when the terms are evaluated.
+// Evaluation transmutes App into Value.
+//
+// HeapObj::App tag corresponds to AP Haskell heap object tag.
+// HeapObj::Pap is PAP: a closure applied to fewer arguments than its arity. It is a value, there is nothing to evaluate.
+// HeapObj::Valu(Value::Closure) tag corresponds to FUN and THUNK Haskell heap object tags.
+// I'm not sure sure what is the i32 representation. Maybe CONSTR?
+// HeapObj::Value(Value::Con) certainly is CONSTR.
+// https://gitlab.haskell.org/ghc/ghc/-/wikis/commentary/rts/storage/heap-objects
+//
+// HeapObj::Case is a thunk which evaluates the scrutinee and continues with the alternative matching its constructor.
+// HeapObj::PrimApp is a saturated application of a primitive operation (see primops.rs).
+// HeapObj::BlackHole replaces a thunk while it is being evaluated (BLACKHOLE in GHC).
+// HeapObj::Ind replaces a thunk when it is evaluated, it points to the value (IND in GHC).
+// Indirections are followed on access and the collector in gc.rs removes them.
+//
+// Values are not allocated in a cell (see HeapPtr), a cell holds a HeapObj::Value only if it was overwritten with one.
+// Arguments are a boxed slice rather than a Vec, which would have made every cell a word bigger.
+#[derive(Clone)]
+pub enum HeapObj {
+    App(HeapPtr, HeapPtr),
+    Value(Value),
+    Case(HeapPtr, Rc<Alts>),
+    PrimApp(&'static PrimOp, Box<[HeapPtr]>),
+    Pap(Closure, Box<[HeapPtr]>),
+    BlackHole,
+    Ind(HeapPtr),
+}
+
+// Alternatives of a case. The branch for a constructor is a function of its fields, which it gets unevaluated.
+// The default branch is used when no constructor matches.
+pub struct Alts {
+    pub branches: Vec<(Tag, HeapPtr)>,
+    pub default: Option<HeapPtr>,
+}
+
+impl Alts {
+    // The branches applied to free variables for the fields, starting at `level`, and the default branch (no tag).
+    // This is how we look into a case stuck on a neutral term.
+    pub(crate) fn open(&self, level: u32) -> Vec<(Option<Tag>, HeapPtr)> {
+        let branches = self.branches.iter().map(|(tag, branch)| {
+            let fields = level..level + tag.arity as u32;
+            (
+                Some(*tag),
+                fields.map(var).fold(branch.clone(), |f, x| ap(&f, &x)),
+            )
+        });
+        let default = self.default.iter().map(|default| (None, default.clone()));
+        branches.chain(default).collect()
+    }
+}
+
+// HeapObj is to be allocated on our "heap" and the memory is managed through reference counting.
+// Cycles are reclaimed by the collector in gc.rs.
+// Thanks to the use of UnsafeCell, when any HeapPtr forces evaluation of HeapObj, all of them will see the change.
+// This allows of implementation of sharing and call-by-need.
+//
+// Inside of heap::Heap::run objects are allocated in an arena instead, and HeapPtr is an index into it (see heap.rs).
+//
+// A HeapPtr is a single word. Heap objects are aligned to 8 bytes, so the lowest 3 bits of a pointer are always zero
+// and we use them as a tag which tells what the rest of the word is:
+// - a reference counted cell, the pointer Rc::into_raw returns,
+// - a cell in a heap::Heap, its index,
+// - a closure or a constructor. These are values, which are never overwritten, so they don't need a cell:
+//   the HeapPtr points straight to them,
+// - a constructor without fields, e.g. True or Nil. The pointer is its Tag, nothing is allocated,
+// - an unboxed i32 in the upper half of the word, `i32(n)` doesn't allocate either,
+// - a free variable, which stands for an unknown value, e.g. an argument while a closure is read back (see readback.rs),
+// - a neutral term, a free variable applied to something (see NeutralObj).
+pub struct HeapPtr {
+    word: usize,
+    // The word hides Rc pointers and indices into thread local heaps, so a HeapPtr must stay in its thread,
+    // as the Rc it used to be did. A bare usize would be Send and Sync.
+    not_send: PhantomData<Rc<()>>,
+}
+
+const TAG_BITS: usize = 0b111;
+const TAG_CELL: usize = 0;
+const TAG_ARENA: usize = 1;
+const TAG_CLOSURE: usize = 2;
+const TAG_CON: usize = 3;
+const TAG_NULLARY: usize = 4;
+const TAG_I32: usize = 5;
+const TAG_VAR: usize = 6;
+const TAG_NEUTRAL: usize = 7;
+
+// Unboxed i32s and arena indices need the upper half of the word, so 32-bit targets are not supported.
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!(
+    "HeapPtr packs tags, unboxed i32s and arena indices into a word, which needs a 64-bit target"
+);
+
+// HeapPtr is neither Send nor Sync: if it was, the calls below would be ambiguous and this wouldn't compile.
+const _: fn() = || {
+    trait AmbiguousIfSend<A> {
+        fn check() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+    impl<T: ?Sized + Send> AmbiguousIfSend<u8> for T {}
+    <HeapPtr as AmbiguousIfSend<_>>::check();
+
+    trait AmbiguousIfSync<A> {
+        fn check() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSync<()> for T {}
+    impl<T: ?Sized + Sync> AmbiguousIfSync<u8> for T {}
+    <HeapPtr as AmbiguousIfSync<_>>::check();
+};
+
+// What the word of a HeapPtr is, according to its tag. The references are as good as the HeapPtr.
+enum Target<'a> {
+    Cell(&'a HeapCell),
+    Arena(heap::Index),
+    Closure(&'a ClosureObj),
+    Con(&'a ConObj),
+    Nullary(Tag),
+    I32(i32),
+    Var(u32),
+    Neutral(&'a NeutralObj),
+}
+
+// The Rc of a HeapPtr, lent without changing the reference count.
+pub(crate) struct RcRef<'a, T: ?Sized> {
+    rc: ManuallyDrop<Rc<T>>,
+    owner: PhantomData<&'a HeapPtr>,
+}
+
+impl<T: ?Sized> RcRef<'_, T> {
+    // safety: `ptr` comes from Rc::into_raw and the Rc outlives the RcRef.
+    unsafe fn new(ptr: *const T) -> Self {
+        RcRef {
+            rc: ManuallyDrop::new(Rc::from_raw(ptr)),
+            owner: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RcRef<'_, T> {
+    type Target = Rc<T>;
+
+    fn deref(&self) -> &Rc<T> {
+        &self.rc
+    }
+}
+
+// What a reference counted cell holds. `thunk` is whether the object was allocated unevaluated: a value
+// found in it later was computed by forcing it, and reusing that value is what call-by-need saves.
+pub(crate) struct HeapCell {
+    obj: UnsafeCell<HeapObj>,
+    thunk: bool,
+}
+
+// Identity of a heap object, e.g. to find the shared ones. Unboxed values are identified by the value.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Addr(usize);
+
+impl HeapPtr {
+    pub fn new(obj: HeapObj) -> Self {
+        // A value is a HeapPtr already, e.g. a closure was allocated and counted by Closure::new.
+        if let HeapObj::Value(value) = obj {
+            return value.ptr;
+        }
+        stats::allocated(&obj);
+        let thunk = matches!(
+            obj,
+            HeapObj::App(_, _) | HeapObj::Case(_, _) | HeapObj::PrimApp(_, _)
+        );
+        match heap::alloc(obj, thunk) {
+            Ok(index) => HeapPtr::from_word(index.pack()),
+            Err(obj) => {
+                let rc = Rc::new(HeapCell {
+                    obj: UnsafeCell::new(obj),
+                    thunk,
+                });
+                gc::register(&rc);
+                HeapPtr::cell(rc)
+            }
+        }
+    }
+
+    fn from_word(word: usize) -> HeapPtr {
+        HeapPtr {
+            word,
+            not_send: PhantomData,
+        }
+    }
+
+    fn tagged<T: ?Sized>(ptr: *const T, tag: usize) -> HeapPtr {
+        let word = ptr as *const () as usize;
+        debug_assert_eq!(word & TAG_BITS, 0, "heap objects are aligned to 8 bytes");
+        HeapPtr::from_word(word | tag)
+    }
+
+    fn cell(rc: Rc<HeapCell>) -> HeapPtr {
+        HeapPtr::tagged(Rc::into_raw(rc), TAG_CELL)
+    }
+
+    fn con_obj(rc: Rc<ConObj>) -> HeapPtr {
+        HeapPtr::tagged(Rc::into_raw(rc), TAG_CON)
+    }
+
+    fn tag(&self) -> usize {
+        self.word & TAG_BITS
+    }
+
+    fn untagged<T>(&self) -> *const T {
+        (self.word & !TAG_BITS) as *const T
+    }
+
+    fn target(&self) -> Target<'_> {
+        // safety: The tag tells what the pointer points to, and it is alive as long as self is.
+        unsafe {
+            match self.tag() {
+                TAG_CELL => Target::Cell(&*self.untagged()),
+                TAG_ARENA => Target::Arena(heap::Index::unpack(self.word)),
+                TAG_CLOSURE => Target::Closure(&*Closure::widen(self.untagged())),
+                TAG_CON => Target::Con(&*self.untagged()),
+                TAG_NULLARY => Target::Nullary(&*self.untagged()),
+                TAG_I32 => Target::I32((self.word >> 32) as i32),
+                TAG_VAR => Target::Var((self.word >> 32) as u32),
+                _ => Target::Neutral(&*self.untagged()),
+            }
+        }
+    }
+
+    // Whether self and other point to the same object.
+    pub fn ptr_eq(&self, other: &HeapPtr) -> bool {
+        self.word == other.word
+    }
+
+    pub(crate) fn addr(&self) -> Addr {
+        Addr(self.word)
+    }
+
+    // Reference counted cells, which the collector clears when they are garbage.
+    pub(crate) fn rc(&self) -> Option<RcRef<'_, HeapCell>> {
+        // safety: self holds a reference to the cell.
+        (self.tag() == TAG_CELL).then(|| unsafe { RcRef::new(self.untagged()) })
+    }
+
+    // Another reference to the closure self points to.
+    fn closure(&self) -> Option<Closure> {
+        if self.tag() != TAG_CLOSURE {
+            return None;
+        }
+        // safety: The word is the pointer of a Closure, which we only clone.
+        let closure = ManuallyDrop::new(Closure {
+            ptr: self.untagged(),
+        });
+        Some(Closure::clone(&closure))
+    }
+
+    // HeapPtrs and other Rcs pointing to the object. Unboxed values and objects in an arena are not counted.
+    pub(crate) fn strong_count(&self) -> Option<usize> {
+        // safety: The Rcs are only lent, the counts stay as they are.
+        unsafe {
+            match self.tag() {
+                TAG_CELL => Some(Rc::strong_count(&RcRef::<HeapCell>::new(self.untagged()))),
+                TAG_CLOSURE => Some(Rc::strong_count(&RcRef::new(Closure::widen(
+                    self.untagged(),
+                )))),
+                TAG_CON => Some(Rc::strong_count(&RcRef::<ConObj>::new(self.untagged()))),
+                TAG_NEUTRAL => Some(Rc::strong_count(&RcRef::<NeutralObj>::new(self.untagged()))),
+                _ => None,
+            }
+        }
+    }
+
+    // Whether nobody else points to the object. Objects in an arena are not counted, so they may be shared.
+    fn is_unique(&self) -> bool {
+        self.strong_count() == Some(1)
+    }
+
+    // Cells are the objects which can be overwritten, values are not in one.
+    fn is_cell(&self) -> bool {
+        matches!(self.tag(), TAG_CELL | TAG_ARENA)
+    }
+
+    // All accesses to a cell go through here. `f` must not access other HeapObjs, it gets the only reference.
+    fn with_obj<R>(&self, f: impl FnOnce(&mut HeapObj) -> R) -> R {
+        match self.target() {
+            // safety: The unsafe pointer is just temporary, and `f` doesn't make another one.
+            Target::Cell(cell) => f(unsafe { &mut *cell.obj.get() }),
+            Target::Arena(index) => heap::with(index, f),
+            _ => unreachable!("values are not in a cell"),
+        }
+    }
+
+    // Another helper.
+    pub fn value(&self) -> Option<Value> {
+        self.get().value()
+    }
+
+    // Whether self is a value (or a partial application), i.e. forcing it has nothing to do.
+    pub fn is_evaluated(&self) -> bool {
+        if !self.is_cell() {
+            return true;
+        }
+        let target = self.with_obj(|obj| match obj {
+            HeapObj::Value(_) | HeapObj::Pap(_, _) => Ok(true),
+            HeapObj::Ind(target) => Err(target.clone()),
+            _ => Ok(false),
+        });
+        target.unwrap_or_else(|target| target.is_evaluated())
+    }
+
+    // Whether self was allocated as a thunk, so that if it is evaluated now, it was forced before.
+    pub(crate) fn was_thunk(&self) -> bool {
+        match self.target() {
+            Target::Cell(cell) => cell.thunk,
+            Target::Arena(index) => heap::is_thunk(index),
+            _ => false,
+        }
+    }
+
+    // Acessing the HeapObj self is pointing to, or the value it was updated with. It is safe because we return cloned Rc.
+    pub fn get(&self) -> HeapObj {
+        let mut obj = self.raw();
+        while let HeapObj::Ind(target) = obj {
+            obj = target.raw();
+        }
+        obj
+    }
+
+    // The HeapObj in self's cell, indirections included.
+    fn raw(&self) -> HeapObj {
+        if !self.is_cell() {
+            return HeapObj::Value(Value { ptr: self.clone() });
+        }
+        self.with_obj(|obj| obj.clone())
+    }
+
+    // The object at the end of the chain of indirections starting at self.
+    fn follow(&self) -> HeapPtr {
+        let mut ptr = self.clone();
+        while let HeapObj::Ind(target) = ptr.raw() {
+            ptr = target;
+        }
+        ptr
+    }
+
+    // Overwrites the object in place, everybody pointing to it sees the change.
+    pub fn set(&self, obj: HeapObj) {
+        assert!(self.is_cell(), "values can't be overwritten");
+        // The old object is dropped only afterwards, dropping it may drop other objects.
+        let old = self.with_obj(|slot| std::mem::replace(slot, obj));
+        drop(old);
+    }
+
+    // This function implements the core of laxy call-by-need evaluation.
+    // If HeapObj::Value is forced, nothing happens, but when HeapObj::App(f, arg) is forced:
+    // - we force f first,
+    // - we check that f is now a Closure, (i32 is a 'type' error),
+    // - we apply the closure to the (unforced) argument,
+    // - we contineu forcing (the result) until we get a value,
+    // - and finally we overwrite App(f, arg) in-place with an indirection to the result.
+    // At this point the result (i32 or closure) is returned and can be inspected.
+    //
+    // Overwriting App(f, arg) with a copy of the result would work for values, but copying is not free
+    // (constructor fields are a Vec) and it would be wrong for anything which is still to be evaluated: two copies of
+    // a thunk are evaluated twice. An indirection keeps a single object.
+    //
+    // Instead of recursing, we keep what is left to do on an explicit stack, like the STG machine does:
+    // when we enter App(f, arg), we push an update frame for the App and an argument frame for arg, and go on with f.
+    // When we reach a value, the top frame tells what to do with it.
+    //
+    // While an App is being evaluated, it is overwritten with a BlackHole. If evaluation demands it again,
+    // it depends on its own value and we report <<loop>> instead of looping forever.
+    pub fn try_force(&self) -> Result<Value, EvalError> {
+        self.try_force_with(EvalStrategy::Need, EvalLimits::default(), |_| {})
+    }
+
+    // Forces self by name or by value instead, see EvalStrategy.
+    pub fn try_force_by(&self, strategy: EvalStrategy) -> Result<Value, EvalError> {
+        self.try_force_with(strategy, EvalLimits::default(), |_| {})
+    }
+
+    // Same, but evaluation stops at `limits`, and `on_step` gets to look at the evaluator before every step,
+    // e.g. to draw the heap (see dot.rs).
+    pub fn try_force_with(
+        &self,
+        strategy: EvalStrategy,
+        limits: EvalLimits,
+        mut on_step: impl FnMut(Step),
+    ) -> Result<Value, EvalError> {
+        let mut stack: Vec<Frame> = vec![];
+        let result = self.clone().run(&mut stack, strategy, limits, &mut on_step);
+        if result.is_err() {
+            // Put the thunks we were in the middle of evaluating back, so they can be forced again.
+            for frame in stack {
+                if let Frame::Update(ptr, thunk) = frame {
+                    ptr.set(thunk);
+                }
+            }
+        }
+        result
+    }
+
+    fn run(
+        self,
+        stack: &mut Vec<Frame>,
+        strategy: EvalStrategy,
+        limits: EvalLimits,
+        on_step: &mut impl FnMut(Step),
+    ) -> Result<Value, EvalError> {
+        let mut current = self;
+        stats::demanded(&current);
+        loop {
+            stats::depth(stack.len());
+            on_step(Step {
+                current: &current,
+                stack,
+            });
+            let whnf = match current.raw() {
+                HeapObj::App(t1, t2) => {
+                    if !limits.allow(stack.len()) {
+                        return Err(EvalError::ResourceExhausted("continuation stack"));
+                    }
+                    stats::forced();
+                    push_update(
+                        stack,
+                        strategy,
+                        current,
+                        HeapObj::App(t1.clone(), t2.clone()),
+                    );
+                    stack.push(Frame::Arg(t2));
+                    stats::demanded(&t1);
+                    current = t1;
+                    continue;
+                }
+                HeapObj::Case(scrutinee, alts) => {
+                    if !limits.allow(stack.len()) {
+                        return Err(EvalError::ResourceExhausted("continuation stack"));
+                    }
+                    stats::forced();
+                    push_update(
+                        stack,
+                        strategy,
+                        current,
+                        HeapObj::Case(scrutinee.clone(), alts.clone()),
+                    );
+                    stack.push(Frame::Case(alts));
+                    stats::demanded(&scrutinee);
+                    current = scrutinee;
+                    continue;
+                }
+                HeapObj::PrimApp(op, args) => {
+                    if !limits.allow(stack.len()) {
+                        return Err(EvalError::ResourceExhausted("continuation stack"));
+                    }
+                    stats::forced();
+                    push_update(stack, strategy, current, HeapObj::PrimApp(op, args.clone()));
+                    current = match op.strict.iter().position(|&strict| strict) {
+                        Some(i) => {
+                            let arg = args[i].clone();
+                            stats::demanded(&arg);
+                            stack.push(Frame::Prim(op, args, i));
+                            arg
+                        }
+                        None => (op.code)(&args)?,
+                    };
+                    continue;
+                }
+                HeapObj::Ind(target) => {
+                    current = target;
+                    continue;
+                }
+                HeapObj::BlackHole => return Err(EvalError::BlackHole(current)),
+                // A Value or a Pap.
+                whnf => whnf,
+            };
+            match stack.pop() {
+                None => return Ok(whnf.value().expect("evaluated")),
+                // A neutral term is stuck, what was to be done with it goes into its spine instead.
+                Some(Frame::Arg(t2) | Frame::Field(t2)) if whnf.is_neutral() => {
+                    current = stuck(whnf, Elim::App(t2))
+                }
+                Some(Frame::Case(alts)) if whnf.is_neutral() => {
+                    current = stuck(whnf, Elim::Case(alts))
+                }
+                Some(Frame::Prim(op, args, i)) if whnf.is_neutral() => {
+                    current = stuck(whnf, Elim::Prim(op, args, i))
+                }
+                Some(frame @ (Frame::Arg(_) | Frame::Field(_))) => {
+                    let (t2, by_value) = match frame {
+                        Frame::Arg(t2) => (t2, strategy == EvalStrategy::Value),
+                        Frame::Field(t2) => (t2, false),
+                        _ => unreachable!(),
+                    };
+                    let (closure, mut args) = match whnf {
+                        HeapObj::Pap(closure, args) => (closure, args.into_vec()),
+                        whnf => match whnf.value().and_then(Value::closure) {
+                            Some(closure) => (closure, vec![]),
+                            None => return Err(EvalError::NotAFunction(current)),
+                        },
+                    };
+                    // t2.force();
+                    // Forcing the argument would effectively implement call by value. EvalStrategy::Value does it
+                    // without the Rust stack: we evaluate t2 and then come back to the function.
+                    if by_value && !t2.is_evaluated() {
+                        stack.push(Frame::Arg(t2.clone()));
+                        stack.push(Frame::Fun(current));
+                        current = t2;
+                        continue;
+                    }
+                    args.push(t2);
+                    // This is eval/apply: the closure takes as many arguments from the stack as its arity says.
+                    // `f a b` is App(App(f, a), b), so there is an update frame for App(f, a) between the arguments.
+                    while args.len() < closure.arity {
+                        match stack.pop() {
+                            Some(Frame::Arg(arg))
+                                if strategy != EvalStrategy::Value || arg.is_evaluated() =>
+                            {
+                                args.push(arg)
+                            }
+                            Some(Frame::Field(field)) => args.push(field),
+                            frame => {
+                                stack.extend(frame);
+                                break;
+                            }
+                        }
+                    }
+                    if args.len() == closure.arity {
+                        stats::called();
+                        current = closure.call(&args);
+                        continue;
+                    }
+                    // Not enough arguments, the result is a partial application. If it is the value of a thunk,
+                    // the thunk is overwritten with it and no new object is needed.
+                    let pap = HeapObj::Pap(closure, args.into_boxed_slice());
+                    current = match stack.pop() {
+                        Some(Frame::Update(ptr, _)) => {
+                            ptr.set(pap);
+                            stats::updated();
+                            ptr
+                        }
+                        frame => {
+                            stack.extend(frame);
+                            HeapPtr::new(pap)
+                        }
+                    };
+                }
+                Some(Frame::Case(alts)) => {
+                    let value = whnf.value();
+                    let con = value.as_ref().and_then(Value::as_con);
+                    let branch =
+                        con.and_then(|(tag, _)| alts.branches.iter().find(|(t, _)| *t == tag));
+                    current = match (branch, con, &alts.default) {
+                        // Apply the branch to the fields, which go on the stack like arguments of an application.
+                        (Some((_, branch)), Some((_, fields)), _) => {
+                            stack.extend(fields.iter().rev().cloned().map(Frame::Field));
+                            branch.clone()
+                        }
+                        // Only the default matches what is not a constructor: `case 5 of { x -> x }` is 5.
+                        (_, _, Some(default)) => default.clone(),
+                        (_, Some(_), None) => return Err(EvalError::MatchFailure(current)),
+                        (_, None, None) => {
+                            return Err(EvalError::TypeMismatch {
+                                expected: "constructor",
+                                found: current,
+                            })
+                        }
+                    };
+                }
+                Some(Frame::Prim(op, mut args, i)) => {
+                    // The argument is evaluated now, `current` points to its value.
+                    args[i] = current;
+                    current = match (i + 1..args.len()).find(|&j| op.strict[j]) {
+                        Some(j) => {
+                            let arg = args[j].clone();
+                            stats::demanded(&arg);
+                            stack.push(Frame::Prim(op, args, j));
+                            arg
+                        }
+                        None => (op.code)(&args)?,
+                    };
+                }
+                Some(Frame::Fun(f)) => {
+                    // The argument below is evaluated now, back to the function.
+                    current = f;
+                }
+                Some(Frame::Update(ptr, _)) => {
+                    debug_assert!(!ptr.ptr_eq(&current));
+                    ptr.set(HeapObj::Ind(current.clone()));
+                    stats::updated();
+                }
+            }
+        }
+    }
+
+    // Panicking version of try_force, for code that knows the term is well behaved.
+    pub fn force(&self) {
+        if let Err(e) = self.try_force() {
+            panic!("{e}");
+        }
+    }
+
+    // Forces self and checks that the result is an integer.
+    pub fn try_i32(&self) -> Result<i32, EvalError> {
+        match self.try_force()?.i32() {
+            Some(i) => Ok(i),
+            None => Err(EvalError::TypeMismatch {
+                expected: "i32",
+                found: self.clone(),
+            }),
+        }
+    }
+
+    // Forces self to normal form: also the fields of constructors, their fields and so on, like Haskell's deepseq.
+    // Thunks are updated in place as usual and every object is visited once, so sharing is preserved
+    // and cyclic values like `ones` are fine.
+    //
+    // There is no limit on how much is forced. A value which is infinite without being a cycle in the heap,
+    // e.g. `letrec from n = Cons n (from (n + 1)) in from 0`, is forced until the memory runs out, nothing stops it.
+    // readback::readback_with stops at a given depth, for values which may be infinite.
+    pub fn deep_force(&self) -> Result<Value, EvalError> {
+        self.deep(false)
+    }
+
+    // Same, but also under lambdas: closures are applied to free variables (see readback.rs) and their bodies are
+    // forced to normal form too. The closures don't change, but the thunks they capture are evaluated once and for all.
+    //
+    // Unbounded too, and under lambdas more terms are infinite: `letrec f = \x. f` is fine, f x is f again,
+    // but `letrec f = \x. \y. f y` makes a new closure on every call and is normalized forever.
+    pub fn normalize(&self) -> Result<Value, EvalError> {
+        self.deep(true)
+    }
+
+    fn deep(&self, under_lambdas: bool) -> Result<Value, EvalError> {
+        let value = self.try_force()?;
+        // The HeapPtrs keep the objects alive, so their addresses are not reused while we look.
+        let mut seen: HashMap<Addr, HeapPtr> = HashMap::new();
+        // Not recursive, a long list would overflow the Rust stack. The level is that of the next free variable.
+        let mut todo = vec![(self.clone(), 0)];
+        while let Some((ptr, level)) = todo.pop() {
+            let value = ptr.try_force()?;
+            let ptr = ptr.follow();
+            if seen.insert(ptr.addr(), ptr).is_some() {
+                continue;
+            }
+            match value.ptr.target() {
+                Target::Con(con) => {
+                    todo.extend(con.fields.iter().map(|field| (field.clone(), level)))
+                }
+                Target::Closure(closure) if under_lambdas => {
+                    let params = level..level + closure.arity as u32;
+                    let vars: Vec<HeapPtr> = params.clone().map(var).collect();
+                    todo.push((closure.call(&vars), params.end));
+                }
+                // The arguments of a neutral term, and the branches of a stuck case, which bind the fields.
+                Target::Neutral(neutral) => {
+                    todo.push((neutral.head.clone(), level));
+                    match &neutral.elim {
+                        Elim::App(arg) => todo.push((arg.clone(), level)),
+                        Elim::Prim(_, args, _) => {
+                            todo.extend(args.iter().map(|arg| (arg.clone(), level)))
+                        }
+                        Elim::Case(alts) if under_lambdas => {
+                            for (tag, branch) in alts.open(level) {
+                                todo.push((branch, level + tag.map_or(0, |tag| tag.arity as u32)));
+                            }
+                        }
+                        Elim::Case(_) => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(value)
+    }
+}
+
+// Another neutral term, see NeutralObj.
+fn stuck(head: HeapObj, elim: Elim) -> HeapPtr {
+    let HeapObj::Value(Value { ptr: head }) = head else {
+        unreachable!("a neutral term is a value")
+    };
+    let neutral = Rc::new(NeutralObj { head, elim });
+    stats::allocated_neutral();
+    HeapPtr::tagged(Rc::into_raw(neutral), TAG_NEUTRAL)
+}
+
+impl HeapObj {
+    fn is_neutral(&self) -> bool {
+        matches!(self, HeapObj::Value(value) if value.is_neutral())
+    }
+
+    // What an evaluated object looks like from the outside.
+    fn value(self) -> Option<Value> {
+        match self {
+            HeapObj::Value(value) => Some(value),
+            // A partial application is a function of the missing arguments.
+            HeapObj::Pap(closure, args) => {
+                let arity = closure.arity - args.len();
+                let code =
+                    move |args: &[HeapPtr], rest: &[HeapPtr]| closure.call(&[args, rest].concat());
+                let ptr = HeapPtr::from(Closure::new(args.into_vec(), arity, code));
+                Some(Value { ptr })
+            }
+            _ => None,
+        }
+    }
+}
+
+// The evaluator between two steps: the object it looks at and what is left to do.
+pub struct Step<'a> {
+    pub current: &'a HeapPtr,
+    stack: &'a [Frame],
+}
+
+impl Step<'_> {
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    // The HeapPtrs the evaluator's stack holds on to. The thunks being evaluated are among them, blackholed.
+    pub fn stack_ptrs(&self) -> Vec<&HeapPtr> {
+        let mut ptrs = vec![];
+        for frame in self.stack {
+            match frame {
+                Frame::Arg(ptr) | Frame::Field(ptr) | Frame::Fun(ptr) | Frame::Update(ptr, _) => {
+                    ptrs.push(ptr)
+                }
+                Frame::Case(alts) => ptrs.extend(
+                    alts.branches
+                        .iter()
+                        .map(|(_, branch)| branch)
+                        .chain(&alts.default),
+                ),
+                Frame::Prim(_, args, _) => ptrs.extend(args.iter()),
+            }
+        }
+        ptrs
+    }
+}
+
+impl Clone for HeapPtr {
+    fn clone(&self) -> Self {
+        // safety: self holds a reference to the object, the clone gets another one.
+        unsafe {
+            match self.tag() {
+                TAG_CELL => Rc::increment_strong_count(self.untagged::<HeapCell>()),
+                TAG_CLOSURE => Rc::increment_strong_count(Closure::widen(self.untagged())),
+                TAG_CON => Rc::increment_strong_count(self.untagged::<ConObj>()),
+                TAG_NEUTRAL => Rc::increment_strong_count(self.untagged::<NeutralObj>()),
+                _ => {}
+            }
+        }
+        HeapPtr::from_word(self.word)
+    }
+}
+
+thread_local! {
+    // HeapPtrs dropped while another one is, to be dropped after it. None when no HeapPtr is being dropped.
+    static DROPPING: RefCell<Option<Vec<HeapPtr>>> = const { RefCell::new(None) };
+}
+
+// The object is freed together with its last HeapPtr, or with its Heap.
+// Values count themselves, see ClosureObj, ConObj and NeutralObj.
+//
+// Dropping the last HeapPtr to an object drops the HeapPtrs in it, and so on: Drop alone would recurse once
+// per cell of a list and overflow the Rust stack on a long one. So the HeapPtrs dropped meanwhile are put aside
+// in DROPPING, and the outermost drop drops them in a loop.
+impl Drop for HeapPtr {
+    fn drop(&mut self) {
+        if matches!(self.tag(), TAG_ARENA | TAG_NULLARY | TAG_I32 | TAG_VAR) {
+            return;
+        }
+        // If another HeapPtr is being dropped, self is left to it.
+        let nested = DROPPING.try_with(|dropping| {
+            let mut dropping = dropping.borrow_mut();
+            match dropping.as_mut() {
+                Some(later) => {
+                    later.push(HeapPtr::from_word(self.word));
+                    true
+                }
+                None => {
+                    *dropping = Some(vec![]);
+                    false
+                }
+            }
+        });
+        match nested {
+            Ok(true) => {}
+            // safety: self and the HeapPtrs in DROPPING are released once, and never used again.
+            Ok(false) => unsafe {
+                self.release();
+                while let Some(ptr) =
+                    DROPPING.with_borrow_mut(|dropping| dropping.as_mut().and_then(Vec::pop))
+                {
+                    ManuallyDrop::new(ptr).release();
+                }
+                DROPPING.set(None);
+            },
+            // The thread is exiting and DROPPING is gone already.
+            Err(_) => unsafe { self.release() },
+        }
+    }
+}
+
+impl HeapPtr {
+    // Gives up the reference to the object.
+    // safety: the HeapPtr must not be used or dropped afterwards.
+    unsafe fn release(&mut self) {
+        unsafe {
+            match self.tag() {
+                TAG_CELL => {
+                    let rc = Rc::from_raw(self.untagged::<HeapCell>());
+                    if Rc::strong_count(&rc) == 1 {
+                        stats::freed();
+                    }
+                }
+                TAG_CLOSURE => drop(Closure {
+                    ptr: self.untagged(),
+                }),
+                TAG_CON => drop(Rc::from_raw(self.untagged::<ConObj>())),
+                TAG_NEUTRAL => drop(Rc::from_raw(self.untagged::<NeutralObj>())),
+                _ => {}
+            }
+        }
+    }
+}
+
+// How arguments are passed to functions. Rust closures which force their arguments themselves
+// (e.g. with HeapPtr::force) always do it by need.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvalStrategy {
+    // Unevaluated, and every thunk is overwritten with its value, so it is evaluated at most once.
+    #[default]
+    Need,
+    // Unevaluated, and thunks are not updated: a thunk is evaluated again every time its value is needed.
+    // Nothing is blackholed either, so a loop like `x = id x` runs forever instead of failing with <<loop>>.
+    Name,
+    // Evaluated before the call, so an argument which fails or loops fails the call even if it is not used.
+    // Constructor fields stay lazy, only the application is strict.
+    Value,
+}
+
+// Before evaluating a thunk we remember to overwrite it with the value, and blackhole it meanwhile.
+// Skipping the overwrite (and re-evaluating the App on every force) results in call-by-name.
+fn push_update(stack: &mut Vec<Frame>, strategy: EvalStrategy, thunk: HeapPtr, obj: HeapObj) {
+    if strategy != EvalStrategy::Name {
+        thunk.set(HeapObj::BlackHole);
+        stack.push(Frame::Update(thunk, obj));
+    }
+}
+
+// What is left to do after the current object is evaluated.
+enum Frame {
+    // Apply the value to this argument.
+    Arg(HeapPtr),
+    // Same, with a field of a constructor matched by a case. Constructor fields are lazy, so unlike an Arg
+    // it is not evaluated first under call by value.
+    Field(HeapPtr),
+    // Call by value: the value is the argument below, continue with this function.
+    Fun(HeapPtr),
+    // Continue with the alternative matching the value.
+    Case(Rc<Alts>),
+    // The value is the i-th argument of the primop, continue with the next strict one.
+    Prim(&'static PrimOp, Box<[HeapPtr]>, usize),
+    // Overwrite this (blackholed) thunk with the value, so other HeapPtrs pointing to it see the result.
+    // The original thunk is kept to restore it if evaluation fails.
+    Update(HeapPtr, HeapObj),
+}
+
+// Bounds on an evaluation. There are none by default: the stack lives on the Rust heap, so evaluation depth is
+// bounded only by memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvalLimits {
+    // Frames on the continuation stack, so that a runaway term like `(\x. x x) (\x. x x)` fails with
+    // EvalError::ResourceExhausted instead of taking all the memory of the process.
+    pub max_stack: Option<usize>,
+}
+
+impl EvalLimits {
+    // Whether one more frame can be pushed on a stack of `depth` frames.
+    fn allow(&self, depth: usize) -> bool {
+        self.max_stack.is_none_or(|max| depth < max)
+    }
+}
+
+// Short description of the pointed object, good enough for error messages.
+impl fmt::Debug for HeapPtr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            HeapObj::App(_, _) | HeapObj::Case(_, _) | HeapObj::PrimApp(_, _) => {
+                write!(f, "<thunk>")
+            }
+            HeapObj::BlackHole => write!(f, "<blackhole>"),
+            HeapObj::Ind(_) => unreachable!("get follows indirections"),
+            obj @ HeapObj::Pap(_, _) => write!(f, "{}", obj.value().expect("evaluated")),
+            HeapObj::Value(v) => match v.as_con() {
+                // Constructors are printed one level deep only, a cyclic list would be printed forever.
+                Some((tag, fields)) if !fields.is_empty() => write!(f, "({} ..)", tag.name),
+                _ => write!(f, "{v}"),
+            },
+        }
+    }
+}
+
+// Ways in which evaluation can go wrong. Where possible the offending heap object is attached.
+#[derive(Debug)]
+pub enum EvalError {
+    // Something other than a closure was applied to an argument, e.g. `5 6`.
+    NotAFunction(HeapPtr),
+    // A value of a wrong kind was found where a specific one was expected.
+    TypeMismatch {
+        expected: &'static str,
+        found: HeapPtr,
+    },
+    // A thunk demanded its own value while being evaluated (GHC's <<loop>>).
+    BlackHole(HeapPtr),
+    // No alternative of a case matched the constructor of the scrutinee.
+    MatchFailure(HeapPtr),
+    // Evaluation hit a limit on the named resource.
+    ResourceExhausted(&'static str),
+    // A primop failed, e.g. on overflow or division by zero.
+    Arithmetic(String),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::NotAFunction(ptr) => write!(f, "cannot apply {ptr:?}, it is not a function"),
+            EvalError::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {expected}, found {found:?}")
+            }
+            EvalError::BlackHole(_) => write!(f, "<<loop>>"),
+            EvalError::MatchFailure(ptr) => write!(f, "no alternative matches {ptr:?}"),
+            EvalError::ResourceExhausted(resource) => write!(f, "resource exhausted: {resource}"),
+            EvalError::Arithmetic(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+// Finally we learn that Closure is an ordinary Rust closure.
+// Unfortunately it does not have a static size, which depends on the number of captured variables (HeapPtrs).
+// Because of that I was forced to Rc it as well.
+// Inside of a HeapObj that would be a second pointer jump, from the cell to the closure. Closures are values,
+// they are never overwritten, so they don't need a cell: a HeapPtr points to the ClosureObj itself.
+// The code, and with it the variables captured by the Rust closure, is the unsized tail of the same allocation.
+//
+// An Rc<ClosureObj> is a fat pointer, the vtable of the code is next to the pointer. That wouldn't fit in a HeapPtr,
+// so Closure is a thin pointer instead: every ClosureObj starts with a function which makes the fat pointer back.
+//
+// HeapPtrs captured by a Rust closure are invisible to the runtime, so the garbage collector (gc.rs) can't follow them.
+// That's why a closure can also keep its captured variables in `env`, next to the code, which gets them back as the first argument.
+//
+// A closure takes `arity` arguments at once. `\x y. body` as a single closure of arity 2 is called once with both arguments,
+// instead of allocating the intermediate `\y. body` closure for every x.
+#[repr(C)]
+pub struct ClosureObj<F: ?Sized = dyn ClosureCode> {
+    widen: Widen,
+    pub env: Vec<HeapPtr>,
+    pub arity: usize,
+    pub code: F,
+}
+
+type Widen = fn(*const ()) -> *const ClosureObj;
+
+// The fat pointer to a ClosureObj<F>, with the vtable of F.
+fn widen<F: ClosureCode + 'static>(ptr: *const ()) -> *const ClosureObj {
+    ptr as *const ClosureObj<F>
+}
+
+// The pointer of an Rc<ClosureObj>, without the vtable.
+pub struct Closure {
+    ptr: *const (),
+}
+
+impl Closure {
+    // Closures are heap objects of their own, so they are counted in the stats and watched by the collector.
+    pub fn new<F: ClosureCode + 'static>(env: Vec<HeapPtr>, arity: usize, code: F) -> Closure {
+        let widen = widen::<F>;
+        let rc: Rc<ClosureObj> = Rc::new(ClosureObj {
+            widen,
+            env,
+            arity,
+            code,
+        });
+        stats::allocated_closure();
+        gc::register_closure(&rc);
+        Closure::from_rc(rc)
+    }
+
+    fn widen(ptr: *const ()) -> *const ClosureObj {
+        // safety: ClosureObj is repr(C), so every one of them starts with its `widen`.
+        let widen = unsafe { *(ptr as *const Widen) };
+        widen(ptr)
+    }
+
+    fn from_rc(rc: Rc<ClosureObj>) -> Closure {
+        Closure {
+            ptr: Rc::into_raw(rc) as *const (),
+        }
+    }
+}
+
+impl Clone for Closure {
+    fn clone(&self) -> Self {
+        // safety: self holds a reference to the closure, the clone gets another one.
+        unsafe { Rc::increment_strong_count(Closure::widen(self.ptr)) };
+        Closure { ptr: self.ptr }
+    }
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        // safety: self holds a reference to the closure, which is given up here.
+        drop(unsafe { Rc::from_raw(Closure::widen(self.ptr)) })
+    }
+}
+
+impl Deref for Closure {
+    type Target = ClosureObj;
+
+    fn deref(&self) -> &ClosureObj {
+        // safety: The closure is alive as long as self is.
+        unsafe { &*Closure::widen(self.ptr) }
+    }
+}
+
+impl From<Closure> for HeapPtr {
+    fn from(closure: Closure) -> HeapPtr {
+        HeapPtr::tagged(ManuallyDrop::new(closure).ptr, TAG_CLOSURE)
+    }
+}
+
+impl ClosureObj {
+    // `args` has exactly `arity` arguments.
+    pub fn call(&self, args: &[HeapPtr]) -> HeapPtr {
+        debug_assert_eq!(args.len(), self.arity);
+        self.code.call(&self.env, args)
+    }
+}
+
+impl<F: ?Sized> Drop for ClosureObj<F> {
+    fn drop(&mut self) {
+        stats::freed();
+    }
+}
+
+// The code part of a closure: any Rust closure taking the env and the arguments, or a named Combinator.
+pub trait ClosureCode {
+    fn call(&self, env: &[HeapPtr], args: &[HeapPtr]) -> HeapPtr;
+
+    // Rust closures are opaque, only combinators can tell what they are.
+    fn combinator(&self) -> Option<&'static Combinator> {
+        None
+    }
+}
+
+impl<F: Fn(&[HeapPtr], &[HeapPtr]) -> HeapPtr> ClosureCode for F {
+    fn call(&self, env: &[HeapPtr], args: &[HeapPtr]) -> HeapPtr {
+        self(env, args)
+    }
+}
+
+// A defunctionalized closure code: a supercombinator, i.e. a plain function of the environment and the arguments.
+// Closures are then just a code pointer (&'static Combinator) and a Vec of HeapPtrs,
+// so we can tell which code a closure runs, enumerate its captured variables, print or serialize it.
+pub struct Combinator {
+    pub name: &'static str,
+    pub arity: usize,
+    pub code: fn(&[HeapPtr], &[HeapPtr]) -> HeapPtr,
+}
+
+impl ClosureCode for &'static Combinator {
+    fn call(&self, env: &[HeapPtr], args: &[HeapPtr]) -> HeapPtr {
+        (self.code)(env, args)
+    }
+
+    fn combinator(&self) -> Option<&'static Combinator> {
+        Some(self)
+    }
+}
+
+// With the lambda calculus runtime implemented, we move on to examples.
+// We start with some helpers to ease on the rust verboseness (compared to textual lambda calculus).
+
+// Create HeapPtr for the given Rust closure.
+pub fn lambda(f: impl Fn(HeapPtr) -> HeapPtr + 'static) -> HeapPtr {
+    lambda_env(vec![], move |_, x| f(x))
+}
+
+// Same, but the closure gets its captured variables from `env` instead of capturing them in Rust.
+// This way the garbage collector can see them.
+pub fn lambda_env(
+    env: Vec<HeapPtr>,
+    f: impl Fn(&[HeapPtr], HeapPtr) -> HeapPtr + 'static,
+) -> HeapPtr {
+    lambda_n(env, 1, move |env, args| f(env, args[0].clone()))
+}
+
+// A closure taking `arity` arguments at once, `f` gets them all in a slice.
+pub fn lambda_n(
+    env: Vec<HeapPtr>,
+    arity: usize,
+    f: impl Fn(&[HeapPtr], &[HeapPtr]) -> HeapPtr + 'static,
+) -> HeapPtr {
+    assert!(arity > 0, "closures take at least one argument");
+    HeapPtr::from(Closure::new(env, arity, f))
+}
+
+// Create HeapPtr for a closure of the combinator with the given captured variables.
+pub fn comb(combinator: &'static Combinator, env: Vec<HeapPtr>) -> HeapPtr {
+    let arity = combinator.arity;
+    HeapPtr::from(Closure::new(env, arity, combinator))
+}
+
+// Create HeapPtr for i32. We used to box integers, now they are in the HeapPtr itself and nothing is allocated.
+pub fn i32(n: i32) -> HeapPtr {
+    HeapPtr::from_word((n as u32 as usize) << 32 | TAG_I32)
+}
+
+// A free variable, e.g. to evaluate an open term. Readback numbers them by the lambdas around them:
+// the outermost one binds variable 0. Applying it, or looking at it with a case or a primop, makes a neutral term.
+pub fn var(level: u32) -> HeapPtr {
+    HeapPtr::from_word((level as usize) << 32 | TAG_VAR)
+}
+
+// Create HeapPtr for a constructor applied to (unevaluated) fields.
+pub fn con(tag: Tag, fields: Vec<HeapPtr>) -> HeapPtr {
+    assert_eq!(
+        tag.arity,
+        fields.len(),
+        "{} has {} fields",
+        tag.name,
+        tag.arity
+    );
+    if fields.is_empty() {
+        return HeapPtr::tagged(tag, TAG_NULLARY);
+    }
+    let con = Rc::new(ConObj {
+        tag,
+        fields: fields.into_boxed_slice(),
+    });
+    stats::allocated_con();
+    gc::register_con(&con);
+    HeapPtr::con_obj(con)
+}
+
+pub fn bool(b: bool) -> HeapPtr {
+    con(if b { &TRUE } else { &FALSE }, vec![])
+}
+
+// Create HeapPtr for a primop applied to the arguments. With fewer arguments than its arity it is a function of the rest.
+pub fn prim(op: &'static PrimOp, args: Vec<HeapPtr>) -> HeapPtr {
+    assert!(
+        args.len() <= op.arity(),
+        "{} takes {} arguments",
+        op.name,
+        op.arity()
+    );
+    if args.len() == op.arity() {
+        return HeapPtr::new(HeapObj::PrimApp(op, args.into_boxed_slice()));
+    }
+    let arity = op.arity() - args.len();
+    lambda_n(args, arity, move |args, rest| {
+        prim(op, [args, rest].concat())
+    })
+}
+
+// Allocate unevaluated case. `branches` are functions of the constructor fields.
+pub fn case(
+    scrutinee: &HeapPtr,
+    branches: Vec<(Tag, HeapPtr)>,
+    default: Option<HeapPtr>,
+) -> HeapPtr {
+    HeapPtr::new(HeapObj::Case(
+        scrutinee.clone(),
+        Rc::new(Alts { branches, default }),
+    ))
+}
+
+// Allocate unevaluated lambda application.
+pub fn ap(f: &HeapPtr, arg: &HeapPtr) -> HeapPtr {
+    HeapPtr::new(HeapObj::App(f.clone(), arg.clone()))
+}
+
+// Recursive definitions: `letrec(|x| body)` allocates x and then builds body, which may refer to x, in place of x.
+// This ties the knot in the heap, so e.g. `ones = cons 1 ones` is a single cyclic object and its recursion is shared.
+// The knot is not tied while body is being built, so forcing x there is a <<loop>>.
+pub fn letrec(body: impl FnOnce(&HeapPtr) -> HeapPtr) -> HeapPtr {
+    let x = HeapPtr::new(HeapObj::BlackHole);
+    let body = body(&x);
+    if body.is_unique() {
+        // Nobody else points to the body object, so x can take it over.
+        x.set(body.raw());
+    } else if !body.follow().ptr_eq(&x) {
+        // Copying a shared object would duplicate the work of evaluating it, so x forwards to it instead.
+        x.set(HeapObj::Ind(body));
+    }
+    // Otherwise the body is x itself, `letrec x = x`, which stays a BlackHole.
+    x
+}
+
+// fix f = f (fix f), with `fix f` being the same heap object every time.
+pub fn fix(f: &HeapPtr) -> HeapPtr {
+    letrec(|x| ap(f, x))
+}
+
+// We don't have helpers for for "lambda" and "var" constructs in the lambda calculus, because,
+// we use Rust syntax for that. This is so-called to Higher-Order-Abstract-Syntax (HOAS) techique.
+
+// This module has examples of usage of the machinery above.
+#[cfg(test)]
+mod test {
+    use crate::ap;
+    use crate::bool;
+    use crate::case;
+    use crate::comb;
+    use crate::con;
+    use crate::fix;
+    use crate::gc;
+    use crate::i32;
+    use crate::lambda;
+    use crate::lambda_env;
+    use crate::lambda_n;
+    use crate::letrec;
+    use crate::prim;
+    use crate::primops::ADD;
+    use crate::readback::readback;
+    use crate::stats;
+    use crate::var;
+    use crate::Combinator;
+    use crate::Elim;
+    use crate::EvalError;
+    use crate::EvalLimits;
+    use crate::EvalStrategy::{Name, Need, Value};
+    use crate::HeapObj;
+    use crate::HeapPtr;
+    use crate::{CONS, FALSE, JUST, NIL, PAIR, TRUE};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Since most our examples or tests should evaluate to int, this helper reduces the verboseness as well.
+    fn force_expect_i32(ptr: &HeapPtr) -> i32 {
+        ptr.force();
+        ptr.value().unwrap().i32().unwrap()
+    }
+
+    // Simplest application.
+    #[test]
+    fn identity_applied() {
+        // (\x -> x) 5
+        let t = ap(&lambda(|x| x), &i32(5));
+        // assert_eq!(t.get(), 5);
+        assert_eq!(force_expect_i32(&t), 5);
+    }
+
+    // Currying on Rust HOAS.
+    #[test]
+    fn fst_and_snd() {
+        // fst = \x.\y.x
+        let fst = lambda(move |x| lambda(move |y| x.clone()));
+        // snd = \x.\y.y
+        let snd = lambda(move |x| lambda(move |y| y.clone()));
+        // we need to clone 'x' because inner lambda might be called multiple times.
+
+        // fst 5 6 == 5
+        assert_eq!(force_expect_i32(&ap(&ap(&fst, &i32(5)), &i32(6))), 5);
+        // snd 5 6 == 6
+        assert_eq!(force_expect_i32(&ap(&ap(&snd, &i32(5)), &i32(6))), 6);
+    }
+
+    // Verify laziness and call-by-need's memoization.
+    #[test]
+    fn verify_call_by_need() {
+        // The runtime counts closure calls for us.
+        stats::enable();
+        fn get_call_count() -> usize {
+            stats::get().calls
+        }
+        // We define here what in Haskell could be a "build-in" "+1" function.
+        // inc = \n.n + 1
+        let inc = lambda(|x| {
+            // We are lazy, so there is no guarantee that x is a value. Need to force first.
+            i32(force_expect_i32(&x) + 1)
+        });
+
+        // inc_twice = \n.inc (inc x)
+        let inc_twice = lambda(move |n| ap(&inc, &ap(&inc, &n)));
+        // hopefully_12 = inc_twice 10
+        let hopefully_12 = &ap(&inc_twice, &i32(10));
+
+        assert_eq!(get_call_count(), 0);
+        assert_eq!(force_expect_i32(hopefully_12), 12);
+        // inc_twice and inc two times.
+        assert_eq!(get_call_count(), 3);
+        let memo_hits = stats::get().memo_hits;
+        assert_eq!(force_expect_i32(hopefully_12), 12);
+        assert_eq!(get_call_count(), 3);
+        // Indeed nothing happens on second call of force, the value is reused.
+        assert_eq!(stats::get().memo_hits, memo_hits + 1);
+    }
+
+    // The same terms by need, by name and by value.
+    #[test]
+    fn evaluation_strategies() {
+        stats::enable();
+        // (\x. x + x) (1 + 2)
+        let double = || {
+            ap(
+                &lambda(|x| prim(&ADD, vec![x.clone(), x])),
+                &prim(&ADD, vec![i32(1), i32(2)]),
+            )
+        };
+        // By name 1 + 2 is evaluated twice.
+        for (strategy, forced) in [(Need, 3), (Name, 4), (Value, 3)] {
+            stats::reset();
+            assert_eq!(double().try_force_by(strategy).unwrap().i32(), Some(6));
+            assert_eq!(stats::get().forced, forced, "{strategy:?}");
+        }
+        // (\x. 0) (1 2) only succeeds if the argument is not evaluated.
+        let ignore = || ap(&lambda(|_| i32(0)), &ap(&i32(1), &i32(2)));
+        assert_eq!(ignore().try_force_by(Need).unwrap().i32(), Some(0));
+        assert_eq!(ignore().try_force_by(Name).unwrap().i32(), Some(0));
+        assert!(matches!(
+            ignore().try_force_by(Value),
+            Err(EvalError::NotAFunction(_))
+        ));
+        // By value, all arguments of a multi-argument call are evaluated: (\a b. a) 1 (2 3)
+        let k = lambda_n(vec![], 2, |_, args| args[0].clone());
+        let t = || ap(&ap(&k, &i32(1)), &ap(&i32(2), &i32(3)));
+        assert_eq!(t().try_force_by(Need).unwrap().i32(), Some(1));
+        assert!(matches!(
+            t().try_force_by(Value),
+            Err(EvalError::NotAFunction(_))
+        ));
+        // Constructor fields stay lazy, also when a case passes them to its branch:
+        // case Pair 1 (1 2) of { Pair a b -> a }
+        let fst = lambda_n(vec![], 2, |_, args| args[0].clone());
+        let t = || {
+            case(
+                &con(&PAIR, vec![i32(1), ap(&i32(1), &i32(2))]),
+                vec![(&PAIR, fst.clone())],
+                None,
+            )
+        };
+        for strategy in [Need, Name, Value] {
+            assert_eq!(
+                t().try_force_by(strategy).unwrap().i32(),
+                Some(1),
+                "{strategy:?}"
+            );
+        }
+    }
+
+    // Type errors are reported instead of panicking.
+    #[test]
+    fn evaluation_errors() {
+        // 5 6
+        let t = ap(&i32(5), &i32(6));
+        assert!(
+            matches!(t.try_force(), Err(EvalError::NotAFunction(f)) if f.try_i32().unwrap() == 5)
+        );
+        // (\x -> x) is not an integer.
+        let t = ap(&lambda(|x| x), &lambda(|x| x));
+        assert!(matches!(
+            t.try_i32(),
+            Err(EvalError::TypeMismatch {
+                expected: "i32",
+                ..
+            })
+        ));
+        assert_eq!(
+            t.try_i32().unwrap_err().to_string(),
+            "type mismatch: expected i32, found <closure>"
+        );
+    }
+
+    // Deeply nested evaluation does not use the Rust stack.
+    #[test]
+    fn deep_evaluation() {
+        // id (id (... (id 1)))
+        let id = lambda(|x| x);
+        let mut t = i32(1);
+        for _ in 0..100_000 {
+            t = ap(&id, &t);
+        }
+        assert_eq!(force_expect_i32(&t), 1);
+        // k (k (... (k 1 0) ...) 1) 2 where k = \x.\y.x ignores its second argument.
+        let k = lambda(move |x| lambda(move |y| x.clone()));
+        let mut t = i32(1);
+        for i in 0..100_000 {
+            t = ap(&ap(&k, &t), &i32(i));
+        }
+        assert_eq!(force_expect_i32(&t), 1);
+    }
+
+    // Terms which never reach a value are stopped when the continuation stack is over the limit.
+    #[test]
+    fn runaway_evaluation_is_exhausted() {
+        // (\x. x x) (\x. x x)
+        let omega = lambda(|x| ap(&x, &x));
+        let t = ap(&omega, &omega);
+        let limits = EvalLimits {
+            max_stack: Some(1000),
+        };
+        assert!(matches!(
+            t.try_force_with(Need, limits, |_| {}),
+            Err(EvalError::ResourceExhausted(_))
+        ));
+    }
+
+    // A thunk which needs its own value is detected instead of looping forever.
+    #[test]
+    fn blackholing_detects_loops() {
+        // x = id x
+        let id = lambda(|x| x);
+        let x = HeapPtr::new(HeapObj::BlackHole);
+        x.set(HeapObj::App(id.clone(), x.clone()));
+        let e = x.try_force().err().unwrap();
+        assert!(matches!(e, EvalError::BlackHole(_)));
+        assert_eq!(e.to_string(), "<<loop>>");
+        // The thunk is restored after the error, so it is still App (and still loops).
+        assert!(matches!(x.get(), HeapObj::App(_, _)));
+        assert!(matches!(x.try_force(), Err(EvalError::BlackHole(_))));
+        // Break the cycle, otherwise x leaks.
+        x.set(HeapObj::App(id, i32(1)));
+        assert_eq!(force_expect_i32(&x), 1);
+    }
+
+    // Thunks are restored when evaluation fails for other reasons too.
+    #[test]
+    fn failed_evaluation_leaves_no_blackholes() {
+        // t = (\x. 1 x) 2
+        let t = ap(&lambda(|x| ap(&i32(1), &x)), &i32(2));
+        assert!(matches!(t.try_force(), Err(EvalError::NotAFunction(_))));
+        assert!(matches!(t.try_force(), Err(EvalError::NotAFunction(_))));
+    }
+
+    // Defunctionalized closures: fst = \x.\y.x as two combinators.
+    #[test]
+    fn combinators() {
+        // K = \x. K1{x}
+        static K: Combinator = Combinator {
+            name: "K",
+            arity: 1,
+            code: |_, args| comb(&K1, args.to_vec()),
+        };
+        // K1{x} = \y. x
+        static K1: Combinator = Combinator {
+            name: "K1",
+            arity: 1,
+            code: |env, _| env[0].clone(),
+        };
+        let k5 = ap(&comb(&K, vec![]), &i32(5));
+        assert_eq!(force_expect_i32(&ap(&k5, &i32(6))), 5);
+        // k5 is now a closure we can look into.
+        let closure = k5.value().unwrap().closure().unwrap();
+        assert!(std::ptr::eq(closure.code.combinator().unwrap(), &K1));
+        assert_eq!(closure.env.len(), 1);
+        assert_eq!(force_expect_i32(&closure.env[0]), 5);
+        assert_eq!(k5.value().unwrap().to_string(), "<K1>");
+        // Side-by-side with HOAS lambdas.
+        let t = ap(&ap(&comb(&K, vec![]), &lambda(|x| x)), &i32(6));
+        assert_eq!(force_expect_i32(&ap(&t, &i32(7))), 7);
+    }
+
+    // Eval/apply: a closure of arity 3 gets all its arguments in one call.
+    #[test]
+    fn partial_applications() {
+        // f = \a b c. c
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let f = lambda_n(vec![], 3, move |_, args| {
+            counter.set(counter.get() + 1);
+            args[2].clone()
+        });
+        let f1 = ap(&f, &i32(1));
+        let f12 = ap(&f1, &i32(2));
+        assert_eq!(force_expect_i32(&ap(&f12, &i32(3))), 3);
+        assert_eq!(calls.get(), 1);
+        // The applications with too few arguments were evaluated in place to partial applications, which are shared.
+        assert!(matches!(f12.get(), HeapObj::Pap(_, args) if args.len() == 2));
+        assert_eq!(force_expect_i32(&ap(&f12, &i32(4))), 4);
+        assert_eq!(calls.get(), 2);
+        // From the outside, f 1 is a function of the other two arguments.
+        assert_eq!(f1.try_force().unwrap().closure().unwrap().arity, 2);
+        let g = HeapPtr::new(HeapObj::Value(f1.value().unwrap()));
+        assert_eq!(force_expect_i32(&ap(&ap(&g, &i32(5)), &i32(6))), 6);
+        // With too many arguments, the result of the call is applied to the rest: f 0 0 id 7
+        let t = ap(&ap(&ap(&ap(&f, &i32(0)), &i32(0)), &lambda(|x| x)), &i32(7));
+        assert_eq!(force_expect_i32(&t), 7);
+    }
+
+    // Scott encoded lists: cons h t = \c n. c h t, nil = \c n. n.
+    fn cons(h: &HeapPtr, t: &HeapPtr) -> HeapPtr {
+        lambda_env(vec![h.clone(), t.clone()], |env, c| {
+            lambda_env(vec![env[0].clone(), env[1].clone(), c], |env, _| {
+                ap(&ap(&env[2], &env[0]), &env[1])
+            })
+        })
+    }
+
+    fn nil() -> HeapPtr {
+        lambda(|_| lambda(|n| n))
+    }
+
+    // Reads back a list of integers.
+    fn to_vec(list: &HeapPtr) -> Vec<i32> {
+        let mut result = vec![];
+        let mut list = list.clone();
+        // list (\h t. 1) 0 tells whether list is a cons.
+        while force_expect_i32(&ap(&ap(&list, &lambda(|_| lambda(|_| i32(1)))), &i32(0))) == 1 {
+            result.push(force_expect_i32(&ap(
+                &ap(&list, &lambda(|h| lambda(move |_| h.clone()))),
+                &nil(),
+            )));
+            list = ap(&ap(&list, &lambda(|_| lambda(|t| t))), &nil());
+        }
+        result
+    }
+
+    // Recursion with sharing: ones = cons 1 ones is a single heap object.
+    #[test]
+    fn letrec_ties_the_knot() {
+        let before = crate::gc::live_objects();
+        // ones = fix (\xs. cons 1 xs)
+        let one = i32(1);
+        let ones = fix(&lambda_env(vec![one], |env, xs| cons(&env[0], &xs)));
+        // The lambda and `ones` itself, `one` is unboxed.
+        assert_eq!(crate::gc::live_objects() - before, 2);
+
+        // take = \n xs. if n == 0 then nil else xs (\h t. cons h (take (n - 1) t)) nil
+        let take = letrec(|take| {
+            let take = take.clone();
+            lambda(move |n| {
+                let take = take.clone();
+                lambda(move |xs| {
+                    let n = force_expect_i32(&n);
+                    if n == 0 {
+                        return nil();
+                    }
+                    let take = take.clone();
+                    let c = lambda(move |h| {
+                        let take = take.clone();
+                        lambda(move |t| cons(&h, &ap(&ap(&take, &i32(n - 1)), &t)))
+                    });
+                    ap(&ap(&xs, &c), &nil())
+                })
+            })
+        });
+        assert_eq!(to_vec(&ap(&ap(&take, &i32(5)), &ones)), vec![1, 1, 1, 1, 1]);
+
+        // ones was evaluated in place to a cons cell whose tail is ones again.
+        let closure = ones.value().unwrap().closure().unwrap();
+        assert!(closure.env[1].ptr_eq(&ones));
+    }
+
+    #[test]
+    fn letrec_of_itself_is_a_loop() {
+        let x = letrec(|x| x.clone());
+        assert!(matches!(x.try_force(), Err(EvalError::BlackHole(_))));
+    }
+
+    // Algebraic data types: length of a list and the head of a Maybe.
+    #[test]
+    fn constructors_and_case() {
+        // xs = Cons 1 (Cons 2 Nil)
+        let xs = con(
+            &CONS,
+            vec![i32(1), con(&CONS, vec![i32(2), con(&NIL, vec![])])],
+        );
+        // length = \xs. case xs of { Nil -> 0; Cons _ t -> inc (length t) }
+        let length = letrec(|length| {
+            let length = length.clone();
+            lambda(move |xs| {
+                let length = length.clone();
+                let cons_branch = lambda(move |_| {
+                    let length = length.clone();
+                    lambda(move |t| i32(force_expect_i32(&ap(&length, &t)) + 1))
+                });
+                case(&xs, vec![(&NIL, i32(0)), (&CONS, cons_branch)], None)
+            })
+        });
+        assert_eq!(force_expect_i32(&ap(&length, &xs)), 2);
+
+        // The fields of a constructor are lazy: Just (5 6) is fine until we look inside.
+        let just = con(&JUST, vec![ap(&i32(5), &i32(6))]);
+        assert_eq!(just.try_force().unwrap().to_string(), "Just <thunk>");
+        let t = case(&just, vec![(&JUST, lambda(|x| x))], None);
+        assert!(matches!(t.try_force(), Err(EvalError::NotAFunction(_))));
+        // The default branch.
+        let t = case(&bool(false), vec![(&TRUE, i32(1))], Some(i32(0)));
+        assert_eq!(force_expect_i32(&t), 0);
+    }
+
+    #[test]
+    fn case_errors() {
+        let t = case(&bool(true), vec![(&FALSE, i32(0))], None);
+        assert!(matches!(t.try_force(), Err(EvalError::MatchFailure(_))));
+        let t = case(&i32(1), vec![(&FALSE, i32(0))], None);
+        assert!(matches!(
+            t.try_force(),
+            Err(EvalError::TypeMismatch {
+                expected: "constructor",
+                ..
+            })
+        ));
+    }
+
+    // A default branch matches anything, constructor or not.
+    #[test]
+    fn case_defaults() {
+        let t = case(&i32(5), vec![(&FALSE, i32(0))], Some(i32(1)));
+        assert_eq!(force_expect_i32(&t), 1);
+        let f = lambda(|x| x);
+        let t = case(&f, vec![], Some(i32(2)));
+        assert_eq!(force_expect_i32(&t), 2);
+        let t = case(&bool(true), vec![(&FALSE, i32(0))], Some(i32(3)));
+        assert_eq!(force_expect_i32(&t), 3);
+    }
+
+    // Dropping a long list, or a long chain of thunks, doesn't recurse once per object.
+    #[test]
+    fn long_structures_are_dropped() {
+        let live = gc::live_objects();
+        let list = (0..100_000).fold(con(&NIL, vec![]), |list, i| con(&CONS, vec![i32(i), list]));
+        let id = lambda(|x| x);
+        let thunks = (0..100_000).fold(i32(0), |t, _| ap(&id, &t));
+        drop(list);
+        drop(thunks);
+        drop(id);
+        assert_eq!(gc::live_objects(), live);
+    }
+
+    // Normal forms: evaluation under constructors, and under lambdas.
+    #[test]
+    fn deep_forcing() {
+        stats::enable();
+        // let x = 1 + 2 in Pair x (Cons x Nil)
+        let x = prim(&ADD, vec![i32(1), i32(2)]);
+        let pair = con(
+            &PAIR,
+            vec![x.clone(), con(&CONS, vec![x.clone(), con(&NIL, vec![])])],
+        );
+        pair.force();
+        // Weak head normal form is enough for force.
+        assert!(!x.is_evaluated());
+        stats::reset();
+        pair.deep_force().unwrap();
+        // x is shared, it was evaluated once.
+        assert_eq!(stats::get().forced, 1);
+        assert_eq!(readback(&pair).unwrap().to_string(), "Pair 3 (Cons 3 Nil)");
+
+        // Cycles are fine: ones = Cons (0 + 1) ones
+        let ones = letrec(|ones| con(&CONS, vec![prim(&ADD, vec![i32(0), i32(1)]), ones.clone()]));
+        ones.deep_force().unwrap();
+        assert!(ones.value().unwrap().con().unwrap().1[0].is_evaluated());
+
+        // A lambda is a normal form, unless we look under it: let y = 1 + 2 in \_. y
+        let y = prim(&ADD, vec![i32(1), i32(2)]);
+        let f = lambda_env(vec![y.clone()], |env, _| env[0].clone());
+        f.deep_force().unwrap();
+        assert!(!y.is_evaluated());
+        f.normalize().unwrap();
+        assert!(y.is_evaluated());
+
+        // Errors hiding in the fields are found too.
+        let t = con(&JUST, vec![ap(&i32(5), &i32(6))]);
+        assert!(t.try_force().is_ok());
+        assert!(matches!(t.deep_force(), Err(EvalError::NotAFunction(_))));
+    }
+
+    // Free variables: evaluation goes as far as it can and keeps what it got stuck on.
+    #[test]
+    fn open_terms() {
+        let f = var(0);
+        // f (1 + 2) ((\x. x) 4)
+        let t = ap(
+            &ap(&f, &prim(&ADD, vec![i32(1), i32(2)])),
+            &ap(&lambda(|x| x), &i32(4)),
+        );
+        let value = t.try_force().unwrap();
+        assert_eq!(value.to_string(), "x <thunk> <thunk>");
+        // t was updated with the neutral term like with any other value.
+        assert!(t.is_evaluated());
+        let (x, spine) = value.neutral().unwrap();
+        assert_eq!((x, spine.len()), (0, 2));
+        assert!(matches!(&spine[1], Elim::App(arg) if arg.try_i32().unwrap() == 4));
+        // The arguments are evaluated by deep_force, or by readback.
+        t.deep_force().unwrap();
+        assert!(matches!(&spine[0], Elim::App(arg) if arg.is_evaluated()));
+        assert_eq!(readback(&t).unwrap().to_string(), "x 3 4");
+
+        // Stuck cases and primops: 1 + case f of { True -> 2 }
+        let t = prim(&ADD, vec![i32(1), case(&f, vec![(&TRUE, i32(2))], None)]);
+        assert_eq!(t.try_force().unwrap().to_string(), "(+) 1 (case x of {..})");
+        assert_eq!(
+            readback(&t).unwrap().to_string(),
+            "1 + (case x of { True -> 2 })"
+        );
+        // Other values still can't be applied.
+        assert!(matches!(
+            ap(&i32(1), &f).try_force(),
+            Err(EvalError::NotAFunction(_))
+        ));
+    }
+
+    #[test]
+    fn deep_curring_is_awkward() {
+        // f = \a.\b.\c.a
+        let f = lambda(move |a| {
+            lambda(move |b| {
+                let a = a.clone(); // This is needed.
+                lambda(move |c| a.clone())
+            })
+        });
+    }
+}
+// So what did we learn?
+// - (I believe that) Haskell's lambda-lifting (supercombinator synthesis) is very close to Rust's closure forming.
+// - The code of Rust lambdas that are passed to `lambda` are compiled by Rust. This is similar to what Haskell's G-machine is doing to super-combinators.
+// - `lambda` allocates a closure, not a function on the heap, it is a struct containing HeapPtrs to all referenced variables.
+// - This implementation has additional indirection to closures (Rc in Closure), which Rust asks for, but probably is not needed.
+// - `ap` does not call a function but allocates unvaluated object on the heap.
+//
+
+// What could we do next?
+// - Why do we need dyn/Rc in Closure? Isn't Box enough? How to avoid double pointer skipping?
+//   Relevant: https://github.com/rust-lang/rust/issues/24000#issuecomment-479425396
+// - How to change enum Value to union Value? Rc is in a way. ManualDrop?
+//   Rc::into_raw and a tag in the lowest bits of the pointer, see HeapPtr.
+// - We are verbose. How to write a macro that would synthesise the code for the lambdas, including the awkward clones.
+//   `lam!` in macros.rs does it, but variables from outside of the lambda need to be listed by hand.
+// - Runtime `force` keeps its continuation on an explicit stack, but closures which force their arguments still use the Rust stack.
+// - Simplest GC is not hard in itself and would be cool to see it. But it would need an explicit acccess to closure captrued variables, wouldn't it?
+//   It does: see gc.rs and `lambda_env`.
+// - Would Can we turn `force` calls into tail calls (jmp)? It would be nice to be closer to Haskell "jmp continuations".
+// - Would be very cool to have some runtime benchmarks and maybe compute number of allocations.
+//   `cargo bench` runs a few programs and counts allocations, see benches/programs.rs.
+// - Would be even cooler to use [Haskell's benchmarks](https://gitlab.haskell.org/ghc/ghc/-/wikis/building/running-tests/performance-tests)
+// - How could be print body of the lambdas? Abstract interpretation?
+//   Apply them to free variables and read back the result, see readback.rs.
+// - It would be very interesting to have explicit weakening and contraction (instead of Rc?) and be closer to linear lambda calculus.
@@ -0,0 +1,70 @@
+// `lam!(a b c => body)` is `\a b c. body`: a single closure of arity 3, written without the awkward clones.
+//
+// The closure may be called many times, so it can't give away the HeapPtrs it captured:
+// the body gets its own clones of the arguments and captured variables on every call.
+// The macro can't see which other variables the body uses, so captures have to be listed by hand, in brackets:
+// `lam!([inc] n => ap(&inc, &n))`. They are kept in the env of the closure, like with `lambda_n`, where the
+// garbage collector can see them. A variable used but not listed is captured by Rust, out of the collector's sight.
+// The listed variables are cloned into the env, not moved.
+#[macro_export]
+macro_rules! lam {
+    ([$($captured:ident),*] $($param:ident)+ => $body:expr) => {
+        $crate::lambda_n(vec![$($captured.clone()),*], [$(stringify!($param)),+].len(), move |env, args| {
+            #[allow(unused_variables)]
+            let [$($captured),*] = env else { unreachable!("the env holds the captured variables") };
+            $(#[allow(unused_variables)] let $captured = $captured.clone();)*
+            let [$($param),+] = args else { unreachable!("called with the arity") };
+            $(#[allow(unused_variables)] let $param = $param.clone();)+
+            $body
+        })
+    };
+    ($($param:ident)+ => $body:expr) => {
+        $crate::lam!([] $($param)+ => $body)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ap, i32, prim, primops::ADD, HeapPtr, Value};
+
+    fn force_expect_i32(ptr: &HeapPtr) -> i32 {
+        ptr.try_i32().unwrap()
+    }
+
+    #[test]
+    fn curried_lambdas() {
+        // fst = \x.\y.x, snd = \x.\y.y
+        let fst = lam!(x y => x);
+        let snd = lam!(x y => y);
+        assert_eq!(force_expect_i32(&ap(&ap(&fst, &i32(5)), &i32(6))), 5);
+        assert_eq!(force_expect_i32(&ap(&ap(&snd, &i32(5)), &i32(6))), 6);
+        // f = \a.\b.\c.a, without the clones deep_curring_is_awkward needs.
+        let f = lam!(a b c => a);
+        let f1 = ap(&f, &i32(1));
+        let f12 = ap(&f1, &i32(2));
+        assert_eq!(force_expect_i32(&ap(&f12, &i32(3))), 1);
+        // Partial applications are shared and can be called again.
+        assert_eq!(force_expect_i32(&ap(&f12, &i32(4))), 1);
+        assert_eq!(force_expect_i32(&ap(&ap(&f1, &i32(5)), &i32(6))), 1);
+    }
+
+    #[test]
+    fn captured_variables() {
+        let add = lam!(a b => prim(&ADD, vec![a, b]));
+        // inc_twice = \n. add 1 (add 1 n)
+        let one = i32(1);
+        let inc_twice = lam!([add, one] n => ap(&ap(&add, &one), &ap(&ap(&add, &one), &n)));
+        assert_eq!(force_expect_i32(&ap(&inc_twice, &i32(10))), 12);
+        // `add` and `one` were not moved.
+        assert_eq!(force_expect_i32(&ap(&ap(&add, &one), &one)), 2);
+        // They are in the env of the closure, where the collector sees them.
+        let closure = inc_twice.value().and_then(Value::closure);
+        assert_eq!(closure.expect("lam! makes closures").env.len(), 2);
+        // sum3 = \a b c. add a (add b c)
+        let sum3 = lam!([add] a b c => ap(&ap(&add, &a), &ap(&ap(&add, &b), &c)));
+        assert_eq!(
+            force_expect_i32(&ap(&ap(&ap(&sum3, &i32(1)), &i32(2)), &i32(3))),
+            6
+        );
+    }
+}
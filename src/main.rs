@@ -1,244 +1,343 @@
-#![allow(unused_variables)]
-// Reference counting is our GC replacement.
+// A REPL on top of the runtime.
+// Each line is parsed, compiled to a HeapPtr graph and forced to weak head normal form.
+// Top-level `let x = e` definitions are compiled once and stay alive in the environment between lines,
+// so a definition forced by one input is already evaluated for the next one (sharing across inputs).
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant};
+
 use std::rc::Rc;
 
-// We use UnsafeCell to mutate heap objects in-place when forcing lambda evaluation.
-use std::cell::UnsafeCell;
+use call_by_need_in_rust::parser::{parse_decl, parse_term, Decl, Env, Term};
+use call_by_need_in_rust::readback::{readback_with, MAX_DEPTH};
+use call_by_need_in_rust::stats::{self, Stats};
+use call_by_need_in_rust::types::{self, TypeEnv};
+use call_by_need_in_rust::{EvalLimits, EvalStrategy, HeapPtr};
+
+const HELP: &str = "\
+Enter a term to evaluate it, or `let x = term` (`letrec` if recursive) to define a global.
+Commands:
+  :load <file>   evaluate all definitions and terms in the file
+  :readback <t>  evaluate a term completely, also under lambdas, and print it as a term
+  :type <t>      show the type of a term, without evaluating it
+  :stats         show global definitions and what the last evaluation did
+  :strategy <s>  evaluate by need (the default), name or value
+  :limit <n>     stop evaluations at n frames on the stack, or `none` (the default)
+  :help          show this message
+  :quit          exit";
 
-// Value enum makes it easier to add more types to the calculus.
-// Right now we have just Closures and i32.
-// If our calculus was typed, we could use union instead of enum, since we would always know which enum case it is.
-#[derive(Clone)]
-enum Value {
-    I32(i32),
-    Closure(Closure),
+struct Repl {
+    env: Env,
+    // Global names in definition order. Redefinition shadows, but the old HeapPtr stays reachable
+    // from definitions that referred to it.
+    globals: Vec<(String, HeapPtr)>,
+    // Types of the globals. Evaluation is untyped, so globals which are not well typed can be defined too.
+    types: TypeEnv,
+    last_eval: Option<(Duration, Stats)>,
+    strategy: EvalStrategy,
+    limits: EvalLimits,
 }
 
-// This are just some accesseors that make the code less messy.
-impl Value {
-    fn i32(self: Value) -> Option<i32> {
-        if let Value::I32(i) = self {
-            return Some(i);
+// What the REPL should do after a line.
+enum Control {
+    Continue(String),
+    Quit,
+}
+
+impl Repl {
+    fn new() -> Self {
+        stats::enable();
+        Repl {
+            env: Env::default(),
+            globals: vec![],
+            types: TypeEnv::default(),
+            last_eval: None,
+            strategy: EvalStrategy::Need,
+            limits: EvalLimits::default(),
         }
-        None
     }
 
-    fn closure(self: Value) -> Option<Closure> {
-        if let Value::Closure(c) = self {
-            return Some(c);
+    fn line(&mut self, line: &str) -> Result<Control, String> {
+        let line = line.trim();
+        if let Some(command) = line.strip_prefix(':') {
+            let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+            return match command {
+                "q" | "quit" => Ok(Control::Quit),
+                "h" | "help" => Ok(Control::Continue(HELP.to_string())),
+                "s" | "stats" => Ok(Control::Continue(self.stats())),
+                "l" | "load" => self.load(arg.trim()).map(Control::Continue),
+                "readback" => self.readback(arg).map(Control::Continue),
+                "t" | "type" => self.type_of(arg).map(Control::Continue),
+                "strategy" => {
+                    self.strategy = match arg.trim() {
+                        "need" => EvalStrategy::Need,
+                        "name" => EvalStrategy::Name,
+                        "value" => EvalStrategy::Value,
+                        s => {
+                            return Err(format!("unknown strategy '{s}', try need, name or value"))
+                        }
+                    };
+                    Ok(Control::Continue(format!("evaluating by {}", arg.trim())))
+                }
+                "limit" => {
+                    self.limits.max_stack = match arg.trim() {
+                        "none" => None,
+                        n => Some(
+                            n.parse()
+                                .map_err(|_| format!("bad limit '{n}', try a number or none"))?,
+                        ),
+                    };
+                    Ok(Control::Continue(format!("stack limit: {}", arg.trim())))
+                }
+                _ => Err(format!("unknown command :{command}, try :help")),
+            };
+        }
+        if line.is_empty() || line.starts_with("--") {
+            return Ok(Control::Continue(String::new()));
         }
-        None
+        self.eval(line).map(Control::Continue)
     }
-}
-
-// HeapObj represents unevaluead (App) or evaluated lambda calculus terms.
-// When in heap memory, HeapObj will be in UnsafeCell and can be mutated in place when the terms are evaluated.
-// Evaluation transmutes App into Value.
-//
-// HeapObj::App tag corresponds to PAP and AP Haskell heap objects tags.
-// HeapObj::Valu(Value::Closure) tag corresponds to FUN and THUNK Haskell heap object tags.
-// I'm not sure sure what is the i32 representation. Maybe CONSTR?
-// https://gitlab.haskell.org/ghc/ghc/-/wikis/commentary/rts/storage/heap-objects
-#[derive(Clone)]
-enum HeapObj {
-    App(HeapPtr, HeapPtr),
-    Value(Value),
-}
-
-// HeapObj is to be allocated on our "heap" and the memory is managed through reference counting.
-// We do nothing about cycles.
-// Thanks to the use of UnsafeCell, when any HeapPtr forces evaluation of HeapObj, all of them will see the change.
-// This allows of implementation of sharing and call-by-need.
-#[derive(Clone)]
-struct HeapPtr {
-    rc: Rc<UnsafeCell<HeapObj>>,
-}
 
-impl HeapPtr {
-    fn new(obj: HeapObj) -> Self {
-        HeapPtr {
-            rc: Rc::new(UnsafeCell::new(obj)),
+    fn eval(&mut self, src: &str) -> Result<String, String> {
+        match parse_decl(src).map_err(|e| e.to_string())? {
+            Decl::Let(rec, name, term) => {
+                // letrec x = e is compiled as `letrec x = e in x`.
+                let term = if rec {
+                    Term::LetRec(
+                        name.clone(),
+                        Rc::new(term),
+                        Rc::new(Term::Var(name.clone())),
+                    )
+                } else {
+                    term
+                };
+                let ptr = term.compile(&self.env).map_err(|e| e.to_string())?;
+                self.types = self
+                    .types
+                    .extend(&name, types::infer(&term, &self.types).ok());
+                self.env = self.env.extend(&name, ptr.clone());
+                self.globals.push((name.clone(), ptr));
+                Ok(format!("{name} defined"))
+            }
+            Decl::Expr(term) => {
+                let ptr = term.compile(&self.env).map_err(|e| e.to_string())?;
+                stats::reset();
+                let start = Instant::now();
+                let result = ptr.try_force_with(self.strategy, self.limits, |_| {});
+                self.last_eval = Some((start.elapsed(), stats::get()));
+                result
+                    .map(|value| value.to_string())
+                    .map_err(|e| e.to_string())
+            }
         }
     }
 
-    // Another helper.
-    fn value(&self) -> Option<Value> {
-        match self.get() {
-            HeapObj::Value(v) => Some(v.clone()),
-            _ => None,
-        }
+    fn readback(&mut self, src: &str) -> Result<String, String> {
+        let term = parse_term(src).map_err(|e| e.to_string())?;
+        let ptr = term.compile(&self.env).map_err(|e| e.to_string())?;
+        readback_with(&ptr, self.strategy, self.limits, MAX_DEPTH)
+            .map(|term| term.to_string())
+            .map_err(|e| e.to_string())
     }
 
-    // Acessing the HeapObj self is pointing to. It is safe because we return cloned Rc.
-    fn get(&self) -> HeapObj {
-        // safety: The unsafe pointer is just temporary, we clone immediately.
-        unsafe { (*self.rc.get()).clone() }
+    fn type_of(&mut self, src: &str) -> Result<String, String> {
+        let term = parse_term(src).map_err(|e| e.to_string())?;
+        types::infer(&term, &self.types)
+            .map(|ty| ty.to_string())
+            .map_err(|e| e.to_string())
     }
 
-    // set encapsulate the unsafeness of the accessing and mutation of the HeapObj inside of the UnsafeCell.
-    fn set(&self, obj: HeapObj) {
-        // safety: The unsafe pointer is just temporary, no HeapPtr::get is called in parallel, so this is the only unsafe pointer.
-        unsafe {
-            *self.rc.get() = obj;
+    // A file is a sequence of inputs. An input starts at a line with no indentation
+    // and continues over the indented lines that follow, so definitions can span several lines.
+    fn load(&mut self, path: &str) -> Result<String, String> {
+        let src = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let mut inputs: Vec<String> = vec![];
+        for line in src.lines() {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with("--") {
+                continue;
+            }
+            match inputs.last_mut() {
+                Some(input) if trimmed.len() != line.len() => {
+                    input.push('\n');
+                    input.push_str(line);
+                }
+                _ => inputs.push(line.to_string()),
+            }
         }
+        let mut output = vec![];
+        for input in inputs {
+            output.push(self.eval(&input)?);
+        }
+        Ok(output.join("\n"))
     }
 
-    // This function implements the core of laxy call-by-need evaluation.
-    // If HeapObj::Value is forced, nothing happens, but when HeapObj::App(f, arg) is forced:
-    // - we force f first,
-    // - we assume that f is now a Closure, (i32 would be a 'type' error),
-    // - we apply the closure to the (unforced) argument,
-    // - we contineu forcing (the result) until we get a value,
-    // - and finally we overwrite App(f, arg) in-place with the result.
-    // At this point the result (i32 or closure) can be inspected.
-    fn force(&self) {
-        if let HeapObj::App(t1, t2) = self.get() {
-            t1.force();
-            // t2.force();
-            // Forcing the argument would effectively implement call by value, but there are better implementations of CBV.
-            let closure: Closure = t1.value().unwrap().closure().unwrap();
-            let new_ptr: HeapPtr = closure(t2.clone());
-            new_ptr.force();
-            self.set(new_ptr.get());
-            // Replacing the overwrite (last line) with force returning new_ptr.get(), would result in call-by-name.
-        };
+    fn stats(&self) -> String {
+        let evaluated = self
+            .globals
+            .iter()
+            .filter(|(_, ptr)| ptr.is_evaluated())
+            .count();
+        let mut lines = vec![format!(
+            "globals: {} ({} evaluated, {} unevaluated)",
+            self.globals.len(),
+            evaluated,
+            self.globals.len() - evaluated
+        )];
+        if let Some((time, stats)) = self.last_eval {
+            lines.push(format!("last evaluation: {time:?}"));
+            let allocated = stats.allocated;
+            lines.push(format!(
+                "  allocated: {} ({} app, {} closure, {} con, {} case, {} primop, {} pap)",
+                allocated.total(),
+                allocated.app,
+                allocated.closure,
+                allocated.con,
+                allocated.case,
+                allocated.prim_app,
+                allocated.pap
+            ));
+            lines.push(format!(
+                "  forced: {}, updates: {}, calls: {}, memo hits: {}, max depth: {}",
+                stats.forced, stats.updates, stats.calls, stats.memo_hits, stats.max_depth
+            ));
+            lines.push(format!(
+                "  live objects: {} (peak {})",
+                stats.live, stats.peak_live
+            ));
+        }
+        lines.join("\n")
     }
 }
 
-// Finally we learn that Closure is an ordinary Rust closure.
-// Unfortunately it does not have a static size, which depends on the number of captured variables (HeapPtrs).
-// Because of that I was forced to Rc it as well.
-// This additional pointer jumping is probably one "the biggest" inefficiency of this implementation.
-type Closure = Rc<dyn Fn(HeapPtr) -> HeapPtr>;
-
-// With the lambda calculus runtime implemented, we move on to examples.
-// We start with some helpers to ease on the rust verboseness (compared to textual lambda calculus).
-
-// Create HeapPtr for the given Rust closure.
-fn lambda(f: impl Fn(HeapPtr) -> HeapPtr + 'static) -> HeapPtr {
-    HeapPtr::new(HeapObj::Value(Value::Closure(Rc::new(f))))
-}
-
-// Create HeapPtr for i32. We only boxed integers.
-fn i32(n: i32) -> HeapPtr {
-    HeapPtr::new(HeapObj::Value(Value::I32(n)))
-}
-
-// Allocate unevaluated lambda application.
-fn ap(f: &HeapPtr, arg: &HeapPtr) -> HeapPtr {
-    HeapPtr::new(HeapObj::App(f.clone(), arg.clone()))
+fn main() {
+    let mut repl = Repl::new();
+    let mut stdin = io::stdin().lock();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        line.clear();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        match repl.line(&line) {
+            Ok(Control::Continue(output)) if output.is_empty() => {}
+            Ok(Control::Continue(output)) => println!("{output}"),
+            Ok(Control::Quit) => break,
+            Err(e) => println!("error: {e}"),
+        }
+    }
 }
-// We don't have helpers for for "lambda" and "var" constructs in the lambda calculus, because,
-// we use Rust syntax for that. This is so-called to Higher-Order-Abstract-Syntax (HOAS) techique.
 
-// This module has examples of usage of the machinery above.
 #[cfg(test)]
 mod test {
-    use crate::ap;
-    use crate::i32;
-    use crate::lambda;
-    use crate::HeapPtr;
-
-    // Since most our examples or tests should evaluate to int, this helper reduces the verboseness as well.
-    fn force_expect_i32(ptr: &HeapPtr) -> i32 {
-        ptr.force();
-        ptr.value().unwrap().i32().unwrap()
+    use crate::{Control, Repl};
+
+    fn run(repl: &mut Repl, line: &str) -> String {
+        match repl.line(line) {
+            Ok(Control::Continue(output)) => output,
+            Ok(Control::Quit) => "quit".to_string(),
+            Err(e) => format!("error: {e}"),
+        }
     }
 
-    // Simplest application.
     #[test]
-    fn identity_applied() {
-        // (\x -> x) 5
-        let t = ap(&lambda(|x| x), &i32(5));
-        // assert_eq!(t.get(), 5);
-        assert_eq!(force_expect_i32(&t), 5);
+    fn evaluates_and_keeps_definitions() {
+        let mut repl = Repl::new();
+        assert_eq!(run(&mut repl, "(\\x. x) 5"), "5");
+        assert_eq!(run(&mut repl, "let k x y = x"), "k defined");
+        assert_eq!(run(&mut repl, "let five = k 5 6"), "five defined");
+        assert_eq!(run(&mut repl, "k"), "<closure>");
+        assert_eq!(run(&mut repl, ":readback k 1"), "\\x. 1");
+        assert_eq!(run(&mut repl, ":type k five"), "a -> Int");
+        assert!(run(&mut repl, ":stats").starts_with("globals: 2 (1 evaluated, 1 unevaluated)"));
+        assert_eq!(run(&mut repl, "five"), "5");
+        // `five` was updated in place by the previous line.
+        let stats = run(&mut repl, ":stats");
+        assert!(stats.starts_with("globals: 2 (2 evaluated, 0 unevaluated)"));
+        assert!(stats.contains("forced: 2, updates: 2, calls: 1"), "{stats}");
+        assert_eq!(run(&mut repl, "letrec loop = loop"), "loop defined");
+        assert_eq!(run(&mut repl, "loop"), "error: <<loop>>");
+        assert_eq!(run(&mut repl, ":quit"), "quit");
     }
 
-    // Currying on Rust HOAS.
     #[test]
-    fn fst_and_snd() {
-        // fst = \x.\y.x
-        let fst = lambda(move |x| lambda(move |y| x.clone()));
-        // snd = \x.\y.y
-        let snd = lambda(move |x| lambda(move |y| y.clone()));
-        // we need to clone 'x' because inner lambda might be called multiple times.
-
-        // fst 5 6 == 5
-        assert_eq!(force_expect_i32(&ap(&ap(&fst, &i32(5)), &i32(6))), 5);
-        // snd 5 6 == 6
-        assert_eq!(force_expect_i32(&ap(&ap(&snd, &i32(5)), &i32(6))), 6);
+    fn reports_errors() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            run(&mut repl, "x"),
+            "error: parse error at 0: unbound variable x"
+        );
+        assert_eq!(
+            run(&mut repl, "1 2"),
+            "error: cannot apply 1, it is not a function"
+        );
+        assert!(run(&mut repl, ":frobnicate").starts_with("error: unknown command"));
+        assert!(run(&mut repl, ":load /nonexistent").starts_with("error: /nonexistent"));
+        // Globals which are not well typed can be defined, but they have no type.
+        assert_eq!(run(&mut repl, "let bad = 1 2"), "bad defined");
+        assert_eq!(run(&mut repl, ":type bad"), "error: bad is not well typed");
     }
 
-    // Verify laziness and call-by-need's memoization.
     #[test]
-    fn verify_call_by_need() {
-        static mut CALL_COUNT: i32 = 0;
-        fn get_call_count() -> i32 {
-            // safety: single-threaded.
-            unsafe {
-                return CALL_COUNT;
-            }
-        }
-        // We define here what in Haskell could be a "build-in" "+1" function.
-        // inc = \n.n + 1
-        let inc = lambda(|x| {
-            // Tracking call count for test needs.
-            // safety: single-threaded.
-            unsafe {
-                CALL_COUNT += 1;
-            }
-            // We are lazy, so there is no guarantee that x is a value. Need to force first.
-            i32(force_expect_i32(&x) + 1)
-        });
-
-        // inc_twice = \n.inc (inc x)
-        let inc_twice = lambda(move |n| ap(&inc, &ap(&inc, &n)));
-        // hopefully_12 = inc_twice 10
-        let hopefully_12 = &ap(&inc_twice, &i32(10));
-
-        assert_eq!(get_call_count(), 0);
-        assert_eq!(force_expect_i32(&hopefully_12), 12);
-        assert_eq!(get_call_count(), 2);
-        assert_eq!(force_expect_i32(&hopefully_12), 12);
-        assert_eq!(get_call_count(), 2);
-        // Indeed nothing happens on second call of force.
+    fn switches_strategies() {
+        let mut repl = Repl::new();
+        assert_eq!(run(&mut repl, "let x = 1 + 2"), "x defined");
+        assert_eq!(run(&mut repl, ":strategy name"), "evaluating by name");
+        assert_eq!(run(&mut repl, "x * x"), "9");
+        // By name, x is not updated.
+        assert!(run(&mut repl, ":stats").starts_with("globals: 1 (0 evaluated, 1 unevaluated)"));
+        assert_eq!(run(&mut repl, "(\\y. 0) (1 2)"), "0");
+        assert_eq!(run(&mut repl, ":readback \\z. (\\y. z) (1 2)"), "\\x. x");
+        assert_eq!(run(&mut repl, ":strategy value"), "evaluating by value");
+        assert_eq!(
+            run(&mut repl, "(\\y. 0) (1 2)"),
+            "error: cannot apply 1, it is not a function"
+        );
+        // Readback evaluates the same way.
+        assert_eq!(
+            run(&mut repl, ":readback \\z. (\\y. z) (1 2)"),
+            "error: cannot apply 1, it is not a function"
+        );
+        assert!(run(&mut repl, ":strategy lazy").starts_with("error: unknown strategy"));
     }
 
     #[test]
-    fn deep_curring_is_awkward() {
-        // f = \a.\b.\c.a
-        let f = lambda(move |a| {
-            lambda(move |b| {
-                let a = a.clone(); // This is needed.
-                lambda(move |c| a.clone())
-            })
-        });
+    fn limits_the_stack() {
+        let mut repl = Repl::new();
+        let range = "letrec range a b = if a > b then Nil else Cons a (range (a + 1) b)";
+        assert_eq!(run(&mut repl, range), "range defined");
+        let length = "letrec length xs = case xs of { Nil -> 0; Cons h t -> 1 + length t }";
+        assert_eq!(run(&mut repl, length), "length defined");
+        // No limit by default, deep recursion takes only memory.
+        assert_eq!(run(&mut repl, "length (range 1 20000)"), "20000");
+        assert_eq!(run(&mut repl, "let omega = \\x. x x"), "omega defined");
+        assert_eq!(run(&mut repl, ":limit 1000"), "stack limit: 1000");
+        assert_eq!(
+            run(&mut repl, "omega omega"),
+            "error: resource exhausted: continuation stack"
+        );
+        assert_eq!(
+            run(&mut repl, ":readback Just (omega omega)"),
+            "error: resource exhausted: continuation stack"
+        );
+        assert_eq!(run(&mut repl, ":limit none"), "stack limit: none");
+        assert_eq!(run(&mut repl, "(\\x. x) 1"), "1");
+        assert!(run(&mut repl, ":limit lots").starts_with("error: bad limit"));
     }
-}
-// So what did we learn?
-// - (I believe that) Haskell's lambda-lifting (supercombinator synthesis) is very close to Rust's closure forming.
-// - The code of Rust lambdas that are passed to `lambda` are compiled by Rust. This is similar to what Haskell's G-machine is doing to super-combinators.
-// - `lambda` allocates a closure, not a function on the heap, it is a struct containing HeapPtrs to all referenced variables.
-// - This implementation has additional indirection to closures (Rc in Closure), which Rust asks for, but probably is not needed.
-// - `ap` does not call a function but allocates unvaluated object on the heap.
-//
 
-fn main() {
-    // Silence 'dead code warnings'.
-    let t = ap(&lambda(|x| x), &i32(5));
-    t.force();
-    let _i = t.value().unwrap().i32().unwrap();
+    #[test]
+    fn loads_files() {
+        let path = std::env::temp_dir().join(format!("repl-load-{}.lc", std::process::id()));
+        std::fs::write(
+            &path,
+            "-- definitions\nlet k x y =\n  x\nlet one = k 1 2\none\n",
+        )
+        .unwrap();
+        let mut repl = Repl::new();
+        let output = run(&mut repl, &format!(":load {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output, "k defined\none defined\n1");
+        assert_eq!(run(&mut repl, "k one 0"), "1");
+    }
 }
-
-// What could we do next?
-// - Why do we need dyn/Rc in Closure? Isn't Box enough? How to avoid double pointer skipping?
-//   Relevant: https://github.com/rust-lang/rust/issues/24000#issuecomment-479425396
-// - How to change enum Value to union Value? Rc is in a way. ManualDrop?
-// - We are verbose. How to write a macro that would synthesise the code for the lambdas, including the awkward clones.
-// - Runtime `force` have two recursive calles, so Rust stack is a part of the runtime.
-// - Simplest GC is not hard in itself and would be cool to see it. But it would need an explicit acccess to closure captrued variables, wouldn't it?
-// - Would Can we turn `force` calls into tail calls (jmp)? It would be nice to be closer to Haskell "jmp continuations".
-// - Would be very cool to have some runtime benchmarks and maybe compute number of allocations.
-// - Would be even cooler to use [Haskell's benchmarks](https://gitlab.haskell.org/ghc/ghc/-/wikis/building/running-tests/performance-tests)
-// - How could be print body of the lambdas? Abstract interpretation?
-// - It would be very interesting to have explicit weakening and contraction (instead of Rc?) and be closer to linear lambda calculus.
@@ -0,0 +1,1128 @@
+// Textual front-end for the runtime.
+// Writing every program as nested Rust closures gets old quickly, so here we parse a tiny surface syntax:
+//
+//   \x. \y. x          -- lambda (`\x -> x` and `\x y. x` work too)
+//   f a b              -- application by juxtaposition, left associative
+//   42                 -- integer literal
+//   (f a)              -- parentheses
+//   let x = e1 in e2   -- sharing: `e1` is allocated once and every use of `x` points to it
+//   let f x y = e1 in e2  -- sugar for `let f = \x y. e1 in e2`
+//   letrec x = e1 in e2   -- recursive let: `x` is in scope in `e1` too
+//   Cons 1 Nil            -- constructors (see crate::CONSTRUCTORS) are capitalized, they are curried functions
+//   case xs of { Nil -> 0; Cons h t -> h; _ -> 1 }
+//   1 + 2 * 3 == 7     -- primops (see primops.rs): infix operators, `div`, `mod` and `seq`; `(+)` is a function
+//   if c then a else b    -- sugar for `case c of { True -> a; False -> b }`
+//
+// Parsing produces a plain `Term` tree, which is then closure converted and compiled to HeapPtr graph via the HOAS helpers.
+use std::fmt;
+use std::rc::Rc;
+
+use crate::primops::{self, PrimOp};
+use crate::{ap, case, con, i32, lambda_n, letrec, prim, HeapPtr, Tag, CONSTRUCTORS, FALSE, TRUE};
+
+// Named lambda calculus terms. Subterms are in Rc, because lambda bodies are captured by Rust closures
+// and instantiated on every call; cloning the Rc is cheaper than cloning the tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Var(String),
+    Int(i32),
+    Lam(String, Rc<Term>),
+    App(Rc<Term>, Rc<Term>),
+    Let(String, Rc<Term>, Rc<Term>),
+    LetRec(String, Rc<Term>, Rc<Term>),
+    Con(Tag),
+    Case(Rc<Term>, Vec<(Pat, Rc<Term>)>),
+}
+
+// Case patterns: a constructor binding its fields, or a variable matching anything.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pat {
+    Con(Tag, Vec<String>),
+    Var(String),
+}
+
+// Terms are printed in the surface syntax, so that the parser reads back the same term.
+// Only as many parentheses as needed: `prec` is how tightly the context binds the term.
+const APP: u8 = 10;
+const ATOM: u8 = 11;
+
+impl Term {
+    fn fmt_prec(&self, f: &mut fmt::Formatter, prec: u8) -> fmt::Result {
+        // Lambdas, lets and cases extend as far right as possible, so they are parenthesized inside of anything else.
+        let open =
+            |f: &mut fmt::Formatter, needed: bool| if needed { write!(f, "(") } else { Ok(()) };
+        let close =
+            |f: &mut fmt::Formatter, needed: bool| if needed { write!(f, ")") } else { Ok(()) };
+        match self {
+            Term::Var(x) if precedence(x).is_some() => write!(f, "({x})"),
+            Term::Var(x) => write!(f, "{x}"),
+            // There are no negative literals, and the literal 2147483648 would be out of range.
+            Term::Int(i32::MIN) => write!(f, "(0 - {} - 1)", i32::MAX),
+            Term::Int(n) if *n < 0 => write!(f, "(0 - {})", -n),
+            Term::Int(n) => write!(f, "{n}"),
+            Term::Con(tag) => write!(f, "{}", tag.name),
+            Term::Lam(x, body) => {
+                open(f, prec > 0)?;
+                write!(f, "\\{x}. ")?;
+                body.fmt_prec(f, 0)?;
+                close(f, prec > 0)
+            }
+            Term::Let(x, e1, e2) | Term::LetRec(x, e1, e2) => {
+                open(f, prec > 0)?;
+                let keyword = if matches!(self, Term::Let(..)) {
+                    "let"
+                } else {
+                    "letrec"
+                };
+                write!(f, "{keyword} {x} = {e1} in ")?;
+                e2.fmt_prec(f, 0)?;
+                close(f, prec > 0)
+            }
+            Term::Case(scrutinee, alts) => {
+                open(f, prec > 0)?;
+                write!(f, "case {scrutinee} of {{ ")?;
+                for (i, (pat, branch)) in alts.iter().enumerate() {
+                    let separator = if i > 0 { "; " } else { "" };
+                    write!(f, "{separator}{pat} -> {branch}")?;
+                }
+                write!(f, " }}")?;
+                close(f, prec > 0)
+            }
+            Term::App(t1, t2) => match self.binary() {
+                // `(+) a b` is printed as `a + b`.
+                Some((op, a, b)) => {
+                    let op_prec = precedence(op).expect("an operator");
+                    open(f, prec > op_prec)?;
+                    a.fmt_prec(f, op_prec)?;
+                    write!(f, " {op} ")?;
+                    b.fmt_prec(f, op_prec + 1)?;
+                    close(f, prec > op_prec)
+                }
+                None => {
+                    open(f, prec > APP)?;
+                    t1.fmt_prec(f, APP)?;
+                    write!(f, " ")?;
+                    t2.fmt_prec(f, ATOM)?;
+                    close(f, prec > APP)
+                }
+            },
+        }
+    }
+
+    // The operator and the operands of `a op b`.
+    fn binary(&self) -> Option<(&str, &Term, &Term)> {
+        let Term::App(op_a, b) = self else {
+            return None;
+        };
+        let Term::App(op, a) = &**op_a else {
+            return None;
+        };
+        match &**op {
+            Term::Var(op) if precedence(op).is_some() => Some((op, a, b)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_prec(f, 0)
+    }
+}
+
+impl fmt::Display for Pat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pat::Con(tag, vars) => {
+                write!(f, "{}", tag.name)?;
+                vars.iter().try_for_each(|x| write!(f, " {x}"))
+            }
+            Pat::Var(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+// Something went wrong while reading the source. `pos` is a byte offset into the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parse error at {}: {}", self.pos, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Con(String),
+    Int(i32),
+    Lambda,
+    Dot,
+    LParen,
+    RParen,
+    Equals,
+    Let,
+    LetRec,
+    In,
+    Case,
+    Of,
+    If,
+    Then,
+    Else,
+    Op(String),
+    LBrace,
+    RBrace,
+    Semi,
+    Eof,
+}
+
+// Splits the source into (byte offset, token) pairs. `--` starts a comment until the end of the line.
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = src.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        let token = match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '-' if src[pos..].starts_with("--") => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            _ if OP_CHARS.contains(c) => {
+                let mut end = pos;
+                while let Some((i, c)) = chars.next_if(|&(_, c)| OP_CHARS.contains(c)) {
+                    end = i + c.len_utf8();
+                }
+                let token = match &src[pos..end] {
+                    "->" => Token::Dot,
+                    "=" => Token::Equals,
+                    op if precedence(op).is_some() => Token::Op(op.to_string()),
+                    op => {
+                        return Err(ParseError {
+                            pos,
+                            msg: format!("unknown operator {op}"),
+                        })
+                    }
+                };
+                tokens.push((pos, token));
+                continue;
+            }
+            '\\' | 'λ' => Token::Lambda,
+            '.' => Token::Dot,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            ';' => Token::Semi,
+            _ if c.is_ascii_digit() => {
+                let mut end = pos;
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                    end = i + c.len_utf8();
+                }
+                let n = src[pos..end].parse().map_err(|_| ParseError {
+                    pos,
+                    msg: format!("integer literal {} does not fit in i32", &src[pos..end]),
+                })?;
+                tokens.push((pos, Token::Int(n)));
+                continue;
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let mut end = pos;
+                while let Some((i, c)) =
+                    chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || c == '\'')
+                {
+                    end = i + c.len_utf8();
+                }
+                let token = match &src[pos..end] {
+                    "let" => Token::Let,
+                    "letrec" => Token::LetRec,
+                    "in" => Token::In,
+                    "case" => Token::Case,
+                    "of" => Token::Of,
+                    "if" => Token::If,
+                    "then" => Token::Then,
+                    "else" => Token::Else,
+                    name if c.is_uppercase() => Token::Con(name.to_string()),
+                    name => Token::Ident(name.to_string()),
+                };
+                tokens.push((pos, token));
+                continue;
+            }
+            _ => {
+                return Err(ParseError {
+                    pos,
+                    msg: format!("unexpected character {c:?}"),
+                })
+            }
+        };
+        chars.next();
+        tokens.push((pos, token));
+    }
+    tokens.push((src.len(), Token::Eof));
+    Ok(tokens)
+}
+
+const OP_CHARS: &str = "+-*/=<>";
+
+// Binding strength of infix operators, as in Haskell. All of them associate to the left.
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "==" | "/=" | "<" | "<=" | ">" | ">=" => Some(4),
+        "+" | "-" => Some(6),
+        "*" => Some(7),
+        _ => None,
+    }
+}
+
+// Recursive descent parser over the token list:
+//
+//   decl := let ident+ '=' expr | expr
+//   expr := '\' ident+ '.' expr | let ident+ '=' expr 'in' expr | 'case' expr 'of' '{' alts '}'
+//         | 'if' expr 'then' expr 'else' expr | app (op app)*
+//   app  := atom+
+//   let  := 'let' | 'letrec'
+//   alts := pat '->' expr (';' pat '->' expr)* ';'?
+//   pat  := con ident* | ident
+//   atom := ident | con | int | '(' expr ')' | '(' op ')'
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    // Number of `expr` calls we are in.
+    depth: usize,
+}
+
+// The parser is recursive, and so are the passes over the Term afterwards: each level of nesting takes some Rust
+// stack, kilobytes of it in a debug build. Terms nested deeper than this are rejected with a ParseError instead of
+// overflowing the stack, with room to spare on the 2 MB stack of a thread (or a test).
+const MAX_DEPTH: usize = 100;
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn pos(&self) -> usize {
+        self.tokens[self.next].0
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.next].1.clone();
+        if token != Token::Eof {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, msg: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            pos: self.pos(),
+            msg: msg.into(),
+        })
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), ParseError> {
+        if *self.peek() != token {
+            return self.error(format!("expected {what}"));
+        }
+        self.bump();
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        match self.peek().clone() {
+            Token::Ident(name) => {
+                self.bump();
+                Ok(name)
+            }
+            _ => self.error("expected identifier"),
+        }
+    }
+
+    // Parses `let f x y = e` (without the `in` part) into `f` and `\x y. e`.
+    // The flag tells if it was `letrec`.
+    fn binding(&mut self) -> Result<(bool, String, Term), ParseError> {
+        let rec = self.bump() == Token::LetRec;
+        let name = self.ident()?;
+        let mut params = vec![];
+        while let Token::Ident(_) = self.peek() {
+            params.push(self.ident()?);
+        }
+        self.expect(Token::Equals, "'=' in let")?;
+        let body = self.expr()?;
+        Ok((rec, name, lambdas(params, body)))
+    }
+
+    fn decl(&mut self) -> Result<Decl, ParseError> {
+        if !matches!(self.peek(), Token::Let | Token::LetRec) {
+            return Ok(Decl::Expr(self.expr()?));
+        }
+        let (rec, x, e1) = self.binding()?;
+        if *self.peek() == Token::Eof {
+            return Ok(Decl::Let(rec, x, e1));
+        }
+        self.expect(Token::In, "'in' after let binding")?;
+        let e2 = self.expr()?;
+        Ok(Decl::Expr(let_term(rec, x, e1, e2)))
+    }
+
+    fn expr(&mut self) -> Result<Term, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return self.error(format!("terms nested more than {MAX_DEPTH} deep"));
+        }
+        self.depth += 1;
+        let term = self.expr_body();
+        self.depth -= 1;
+        term
+    }
+
+    fn expr_body(&mut self) -> Result<Term, ParseError> {
+        match self.peek() {
+            Token::Lambda => {
+                self.bump();
+                let mut params = vec![self.ident()?];
+                while let Token::Ident(_) = self.peek() {
+                    params.push(self.ident()?);
+                }
+                self.expect(Token::Dot, "'.' or '->' after lambda parameters")?;
+                let body = self.expr()?;
+                Ok(lambdas(params, body))
+            }
+            Token::Let | Token::LetRec => {
+                let (rec, x, e1) = self.binding()?;
+                self.expect(Token::In, "'in' after let binding")?;
+                let e2 = self.expr()?;
+                Ok(let_term(rec, x, e1, e2))
+            }
+            Token::Case => {
+                self.bump();
+                let scrutinee = self.expr()?;
+                self.expect(Token::Of, "'of'")?;
+                self.expect(Token::LBrace, "'{' before case alternatives")?;
+                let mut alts = vec![];
+                while *self.peek() != Token::RBrace {
+                    let pat = self.pat()?;
+                    self.expect(Token::Dot, "'->' after pattern")?;
+                    alts.push((pat, Rc::new(self.expr()?)));
+                    if *self.peek() != Token::Semi {
+                        break;
+                    }
+                    self.bump();
+                }
+                self.expect(Token::RBrace, "'}' after case alternatives")?;
+                Ok(Term::Case(Rc::new(scrutinee), alts))
+            }
+            Token::If => {
+                self.bump();
+                let c = self.expr()?;
+                self.expect(Token::Then, "'then'")?;
+                let a = self.expr()?;
+                self.expect(Token::Else, "'else'")?;
+                let b = self.expr()?;
+                let alts = vec![
+                    (Pat::Con(&TRUE, vec![]), Rc::new(a)),
+                    (Pat::Con(&FALSE, vec![]), Rc::new(b)),
+                ];
+                Ok(Term::Case(Rc::new(c), alts))
+            }
+            _ => self.binary(0),
+        }
+    }
+
+    // Precedence climbing over infix operators. `a + b` is `(+) a b`.
+    fn binary(&mut self, min_precedence: u8) -> Result<Term, ParseError> {
+        let mut lhs = self.app()?;
+        while let Token::Op(op) = self.peek().clone() {
+            let precedence = precedence(&op).expect("checked by tokenize");
+            if precedence < min_precedence {
+                break;
+            }
+            self.bump();
+            let rhs = match self.peek() {
+                Token::Lambda | Token::Let | Token::LetRec | Token::Case | Token::If => {
+                    self.expr()?
+                }
+                _ => self.binary(precedence + 1)?,
+            };
+            let op = Term::App(Rc::new(Term::Var(op)), Rc::new(lhs));
+            lhs = Term::App(Rc::new(op), Rc::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn app(&mut self) -> Result<Term, ParseError> {
+        let mut term = self.atom()?;
+        loop {
+            match self.peek() {
+                Token::Ident(_) | Token::Con(_) | Token::Int(_) | Token::LParen => {
+                    term = Term::App(Rc::new(term), Rc::new(self.atom()?));
+                }
+                // A trailing lambda, let, case or if extends as far right as possible: `f \x. x` is `f (\x. x)`.
+                Token::Lambda | Token::Let | Token::LetRec | Token::Case | Token::If => {
+                    term = Term::App(Rc::new(term), Rc::new(self.expr()?));
+                }
+                _ => return Ok(term),
+            }
+        }
+    }
+
+    fn constructor(&mut self) -> Result<Tag, ParseError> {
+        let Token::Con(name) = self.peek().clone() else {
+            return self.error("expected constructor");
+        };
+        match CONSTRUCTORS.iter().find(|tag| tag.name == name) {
+            Some(tag) => {
+                self.bump();
+                Ok(tag)
+            }
+            None => self.error(format!("unknown constructor {name}")),
+        }
+    }
+
+    fn pat(&mut self) -> Result<Pat, ParseError> {
+        if let Token::Ident(_) = self.peek() {
+            return Ok(Pat::Var(self.ident()?));
+        }
+        let pos = self.pos();
+        let tag = self.constructor()?;
+        let mut vars = vec![];
+        while let Token::Ident(_) = self.peek() {
+            vars.push(self.ident()?);
+        }
+        if vars.len() != tag.arity {
+            return Err(ParseError {
+                pos,
+                msg: format!(
+                    "{} has {} fields, but the pattern binds {}",
+                    tag.name,
+                    tag.arity,
+                    vars.len()
+                ),
+            });
+        }
+        Ok(Pat::Con(tag, vars))
+    }
+
+    fn atom(&mut self) -> Result<Term, ParseError> {
+        match self.peek().clone() {
+            Token::Ident(name) => {
+                self.bump();
+                Ok(Term::Var(name))
+            }
+            Token::Con(_) => Ok(Term::Con(self.constructor()?)),
+            Token::Int(n) => {
+                self.bump();
+                Ok(Term::Int(n))
+            }
+            Token::LParen => {
+                self.bump();
+                if let Token::Op(op) = self.peek().clone() {
+                    self.bump();
+                    self.expect(Token::RParen, "')' after operator")?;
+                    return Ok(Term::Var(op));
+                }
+                let term = self.expr()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(term)
+            }
+            Token::Eof => self.error("unexpected end of input"),
+            token => self.error(format!("unexpected {token:?}")),
+        }
+    }
+}
+
+// \x y. b is sugar for \x. \y. b
+fn lambdas(params: Vec<String>, body: Term) -> Term {
+    params
+        .into_iter()
+        .rev()
+        .fold(body, |body, x| Term::Lam(x, Rc::new(body)))
+}
+
+fn let_term(rec: bool, x: String, e1: Term, e2: Term) -> Term {
+    if rec {
+        Term::LetRec(x, Rc::new(e1), Rc::new(e2))
+    } else {
+        Term::Let(x, Rc::new(e1), Rc::new(e2))
+    }
+}
+
+// Parses the whole source as a single term.
+pub fn parse_term(src: &str) -> Result<Term, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        next: 0,
+        depth: 0,
+    };
+    let term = parser.expr()?;
+    if *parser.peek() != Token::Eof {
+        return parser.error("unexpected input after the end of the term");
+    }
+    term.check_depth()?;
+    Ok(term)
+}
+
+// A top-level input: either a global definition `let x = e` (no `in`) or a term to evaluate.
+// The flag tells if the definition is recursive (`letrec`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decl {
+    Let(bool, String, Term),
+    Expr(Term),
+}
+
+pub fn parse_decl(src: &str) -> Result<Decl, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        next: 0,
+        depth: 0,
+    };
+    let decl = parser.decl()?;
+    if *parser.peek() != Token::Eof {
+        return parser.error("unexpected input after the end of the term");
+    }
+    match &decl {
+        Decl::Let(_, _, term) | Decl::Expr(term) => term.check_depth()?,
+    }
+    Ok(decl)
+}
+
+// Parses and compiles a closed term, ready for HeapPtr::force.
+pub fn parse(src: &str) -> Result<HeapPtr, ParseError> {
+    parse_term(src)?.compile(&Env::default())
+}
+
+// Global variables and the HeapPtrs they are bound to.
+// It is a persistent linked list, so that the REPL can extend it cheaply and keep the old versions.
+#[derive(Clone, Default)]
+pub struct Env(Option<Rc<(String, HeapPtr, Env)>>);
+
+impl Env {
+    pub fn extend(&self, name: &str, ptr: HeapPtr) -> Env {
+        Env(Some(Rc::new((name.to_string(), ptr, self.clone()))))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&HeapPtr> {
+        let mut env = self;
+        while let Some(node) = &env.0 {
+            if node.0 == name {
+                return Some(&node.1);
+            }
+            env = &node.2;
+        }
+        None
+    }
+}
+
+impl Term {
+    // Parentheses are checked by the parser, but `f a b c` is as deep as it is long without them.
+    // Not recursive, that's the point.
+    fn check_depth(&self) -> Result<(), ParseError> {
+        let mut todo = vec![(self, 1)];
+        while let Some((term, depth)) = todo.pop() {
+            if depth > MAX_DEPTH {
+                return Err(ParseError {
+                    pos: 0,
+                    msg: format!("terms nested more than {MAX_DEPTH} deep"),
+                });
+            }
+            match term {
+                Term::Var(_) | Term::Int(_) | Term::Con(_) => {}
+                Term::Lam(_, body) => todo.push((body, depth + 1)),
+                Term::App(a, b) | Term::Let(_, a, b) | Term::LetRec(_, a, b) => {
+                    todo.push((a, depth + 1));
+                    todo.push((b, depth + 1));
+                }
+                Term::Case(scrutinee, alts) => {
+                    todo.push((scrutinee, depth + 1));
+                    todo.extend(alts.iter().map(|(_, body)| (&**body, depth + 1)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The direct subterms, put aside with `leaf` left in their place.
+    fn take_subterms(&mut self, leaf: &Rc<Term>, out: &mut Vec<Rc<Term>>) {
+        match self {
+            Term::Var(_) | Term::Int(_) | Term::Con(_) => {}
+            Term::Lam(_, body) => out.push(std::mem::replace(body, leaf.clone())),
+            Term::App(a, b) | Term::Let(_, a, b) | Term::LetRec(_, a, b) => {
+                out.push(std::mem::replace(a, leaf.clone()));
+                out.push(std::mem::replace(b, leaf.clone()));
+            }
+            Term::Case(scrutinee, alts) => {
+                out.push(std::mem::replace(scrutinee, leaf.clone()));
+                out.extend(std::mem::take(alts).into_iter().map(|(_, body)| body));
+            }
+        }
+    }
+
+    // Compiles the term to the heap graph. Variables not bound in the term are looked up in `env`.
+    // Scoping is checked up front, because lambda bodies are only built when the closure is called.
+    pub fn compile(&self, env: &Env) -> Result<HeapPtr, ParseError> {
+        let mut globals = vec![];
+        let mut frame = vec![];
+        self.free_vars(&mut vec![], &mut globals);
+        for x in &globals {
+            match env.lookup(x) {
+                Some(ptr) => frame.push(ptr.clone()),
+                None if primops::lookup(x).is_some() => {}
+                None => {
+                    return Err(ParseError {
+                        pos: 0,
+                        msg: format!("unbound variable {x}"),
+                    })
+                }
+            }
+        }
+        // Names of primops are in scope unless shadowed by a global.
+        globals.retain(|x| env.lookup(x).is_some());
+        Ok(self.convert(&mut globals).build(&mut frame))
+    }
+
+    // Collects variables used but not bound in the term, in order of first use.
+    fn free_vars<'a>(&'a self, bound: &mut Vec<&'a str>, free: &mut Vec<String>) {
+        match self {
+            Term::Var(x) => {
+                if !bound.contains(&x.as_str()) && !free.contains(x) {
+                    free.push(x.clone());
+                }
+            }
+            Term::Int(_) => {}
+            Term::Lam(x, body) => {
+                bound.push(x);
+                body.free_vars(bound, free);
+                bound.pop();
+            }
+            Term::App(f, a) => {
+                f.free_vars(bound, free);
+                a.free_vars(bound, free);
+            }
+            Term::Let(x, e1, e2) => {
+                e1.free_vars(bound, free);
+                bound.push(x);
+                e2.free_vars(bound, free);
+                bound.pop();
+            }
+            Term::LetRec(x, e1, e2) => {
+                bound.push(x);
+                e1.free_vars(bound, free);
+                e2.free_vars(bound, free);
+                bound.pop();
+            }
+            Term::Con(_) => {}
+            Term::Case(scrutinee, alts) => {
+                scrutinee.free_vars(bound, free);
+                for (pat, body) in alts {
+                    let vars = pat.vars();
+                    bound.extend(vars.iter().map(|x| x.as_str()));
+                    body.free_vars(bound, free);
+                    bound.truncate(bound.len() - vars.len());
+                }
+            }
+        }
+    }
+
+    // Closure conversion: variables become indices into the frame of the enclosing lambda,
+    // and every lambda lists which variables of the enclosing frame it captures.
+    // `scope` names the frame slots; the innermost binding of a name is the last one.
+    fn convert(&self, scope: &mut Vec<String>) -> Code {
+        match self {
+            Term::Var(x) => match scope.iter().rposition(|y| y == x) {
+                Some(i) => Code::Var(i),
+                None => Code::Prim(primops::lookup(x).expect("scope checked"), vec![]),
+            },
+            Term::Int(n) => Code::Int(*n),
+            Term::Lam(x, body) => {
+                let mut captured = vec![];
+                self.free_vars(&mut vec![], &mut captured);
+                // Primops are not captured, they are global.
+                captured.retain(|y| scope.contains(y));
+                let captures = captured
+                    .iter()
+                    .map(|y| scope.iter().rposition(|z| z == y).expect("scope checked"))
+                    .collect();
+                // Nested lambdas `\x. \y. body` become a single closure of all their parameters.
+                let mut params = vec![x.clone()];
+                let mut body = body;
+                while let Term::Lam(y, inner) = &**body {
+                    params.push(y.clone());
+                    body = inner;
+                }
+                let arity = params.len();
+                captured.extend(params);
+                Code::Lam(captures, arity, Rc::new(body.convert(&mut captured)))
+            }
+            Term::App(_, _) => {
+                // Constructors and primops applied to arguments are built directly, without going through the curried function.
+                let mut args = vec![];
+                let mut head = self;
+                while let Term::App(f, a) = head {
+                    args.push(a);
+                    head = f;
+                }
+                args.reverse();
+                let (head, args) = match head {
+                    Term::Con(tag) => {
+                        let fields = args
+                            .iter()
+                            .take(tag.arity)
+                            .map(|a| a.convert(scope))
+                            .collect();
+                        (Code::Con(tag, fields), &args[args.len().min(tag.arity)..])
+                    }
+                    Term::Var(x) if !scope.contains(x) => {
+                        let op = primops::lookup(x).expect("scope checked");
+                        let op_args = args
+                            .iter()
+                            .take(op.arity())
+                            .map(|a| a.convert(scope))
+                            .collect();
+                        (Code::Prim(op, op_args), &args[args.len().min(op.arity())..])
+                    }
+                    _ => (head.convert(scope), &args[..]),
+                };
+                args.iter().fold(head, |f, a| {
+                    Code::App(Rc::new(f), Rc::new(a.convert(scope)))
+                })
+            }
+            Term::Let(x, e1, e2) => {
+                let e1 = e1.convert(scope);
+                scope.push(x.clone());
+                let e2 = e2.convert(scope);
+                scope.pop();
+                Code::Let(Rc::new(e1), Rc::new(e2))
+            }
+            Term::LetRec(x, e1, e2) => {
+                scope.push(x.clone());
+                let e1 = e1.convert(scope);
+                let e2 = e2.convert(scope);
+                scope.pop();
+                Code::LetRec(Rc::new(e1), Rc::new(e2))
+            }
+            Term::Con(tag) => Code::Con(tag, vec![]),
+            Term::Case(scrutinee, alts) => {
+                let scrutinee = scrutinee.convert(scope);
+                let mut branches = vec![];
+                let mut default = None;
+                for (pat, body) in alts {
+                    match pat {
+                        // The branch is a function of the fields.
+                        Pat::Con(tag, vars) => {
+                            let branch = lambdas(vars.clone(), (**body).clone());
+                            branches.push((*tag, Rc::new(branch.convert(scope))));
+                        }
+                        // The default branch sees the scrutinee under the variable name. It matches anything,
+                        // so the alternatives after it are never tried.
+                        Pat::Var(x) => {
+                            scope.push(x.clone());
+                            default = Some(Rc::new(body.convert(scope)));
+                            scope.pop();
+                            break;
+                        }
+                    }
+                }
+                Code::Case(Rc::new(scrutinee), branches, default)
+            }
+        }
+    }
+}
+
+// Closure converted Term, see Term::convert.
+enum Code {
+    Var(usize),
+    Int(i32),
+    // Captured variables of the enclosing frame, arity and body.
+    Lam(Vec<usize>, usize, Rc<Code>),
+    App(Rc<Code>, Rc<Code>),
+    Let(Rc<Code>, Rc<Code>),
+    LetRec(Rc<Code>, Rc<Code>),
+    // Constructor with (at most arity) fields.
+    Con(Tag, Vec<Code>),
+    Case(Rc<Code>, Vec<(Tag, Rc<Code>)>, Option<Rc<Code>>),
+    // Primop with (at most arity) arguments.
+    Prim(&'static PrimOp, Vec<Code>),
+}
+
+impl Code {
+    // Here the HOAS helpers do the real work: a lambda becomes a Rust closure that,
+    // when called, builds the body in a frame made of the captured variables and the arguments.
+    // Captured variables are passed through `lambda_n`, so the garbage collector can see them.
+    fn build(&self, frame: &mut Vec<HeapPtr>) -> HeapPtr {
+        match self {
+            Code::Var(i) => frame[*i].clone(),
+            Code::Int(n) => i32(*n),
+            Code::Lam(captures, arity, body) => {
+                let env = captures.iter().map(|&i| frame[i].clone()).collect();
+                let body = body.clone();
+                lambda_n(env, *arity, move |env, args| {
+                    body.build(&mut [env, args].concat())
+                })
+            }
+            Code::App(f, a) => ap(&f.build(frame), &a.build(frame)),
+            // The bound term is allocated once; all occurrences of the variable share it.
+            Code::Let(e1, e2) => {
+                let ptr = e1.build(frame);
+                frame.push(ptr);
+                let result = e2.build(frame);
+                frame.pop();
+                result
+            }
+            // The bound term refers to itself through the heap, see `letrec`.
+            Code::LetRec(e1, e2) => {
+                let ptr = letrec(|x| {
+                    frame.push(x.clone());
+                    let ptr = e1.build(frame);
+                    frame.pop();
+                    ptr
+                });
+                frame.push(ptr);
+                let result = e2.build(frame);
+                frame.pop();
+                result
+            }
+            Code::Con(tag, fields) => {
+                partial_con(tag, fields.iter().map(|field| field.build(frame)).collect())
+            }
+            Code::Prim(op, args) => prim(op, args.iter().map(|arg| arg.build(frame)).collect()),
+            Code::Case(scrutinee, branches, default) => {
+                let scrutinee = scrutinee.build(frame);
+                let branches = branches
+                    .iter()
+                    .map(|(tag, branch)| (*tag, branch.build(frame)))
+                    .collect();
+                let default = default.as_ref().map(|default| {
+                    frame.push(scrutinee.clone());
+                    let default = default.build(frame);
+                    frame.pop();
+                    default
+                });
+                case(&scrutinee, branches, default)
+            }
+        }
+    }
+}
+
+// A constructor which got only some of its fields is a function of the rest.
+fn partial_con(tag: Tag, fields: Vec<HeapPtr>) -> HeapPtr {
+    if fields.len() == tag.arity {
+        return con(tag, fields);
+    }
+    let arity = tag.arity - fields.len();
+    lambda_n(fields, arity, move |fields, rest| {
+        con(tag, [fields, rest].concat())
+    })
+}
+
+impl Pat {
+    fn vars(&self) -> &[String] {
+        match self {
+            Pat::Con(_, vars) => vars,
+            Pat::Var(x) => std::slice::from_ref(x),
+        }
+    }
+}
+
+// Dropping a deep term would recurse once per level. Subterms owned by nobody else are taken apart in a loop instead.
+impl Drop for Term {
+    fn drop(&mut self) {
+        // Also stops the leaf from making a leaf of its own when it is dropped.
+        if let Term::Var(_) | Term::Int(_) | Term::Con(_) = self {
+            return;
+        }
+        let leaf = Rc::new(Term::Int(0));
+        let mut todo = vec![];
+        self.take_subterms(&leaf, &mut todo);
+        while let Some(term) = todo.pop() {
+            if let Ok(mut term) = Rc::try_unwrap(term) {
+                term.take_subterms(&leaf, &mut todo);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::{parse, parse_decl, parse_term, Decl, Term};
+    use crate::HeapPtr;
+    use std::rc::Rc;
+
+    fn force_expect_i32(ptr: &HeapPtr) -> i32 {
+        ptr.force();
+        ptr.value().unwrap().i32().unwrap()
+    }
+
+    fn eval(src: &str) -> i32 {
+        force_expect_i32(&parse(src).unwrap())
+    }
+
+    #[test]
+    fn parses_lambdas_and_application() {
+        let var = |x: &str| Rc::new(Term::Var(x.to_string()));
+        assert_eq!(
+            parse_term("\\x y. x y y").unwrap(),
+            Term::Lam(
+                "x".to_string(),
+                Rc::new(Term::Lam(
+                    "y".to_string(),
+                    Rc::new(Term::App(Rc::new(Term::App(var("x"), var("y"))), var("y")))
+                ))
+            )
+        );
+        assert_eq!(
+            parse_term("\\x -> x").unwrap(),
+            parse_term("λx. x").unwrap()
+        );
+    }
+
+    #[test]
+    fn evaluates_parsed_terms() {
+        assert_eq!(eval("(\\x. x) 5"), 5);
+        assert_eq!(eval("(\\x. \\y. x) 5 6"), 5);
+        assert_eq!(eval("(\\x y. y) 5 6"), 6);
+        // Nested lambdas are a single closure of arity 2.
+        let k = parse("\\x. \\y. x").unwrap();
+        assert_eq!(k.try_force().unwrap().closure().unwrap().arity, 2);
+        assert_eq!(eval("let id = \\x. x in id id 7"), 7);
+        assert_eq!(eval("-- comment\n(\\f. f 1) \\x. x"), 1);
+        assert_eq!(eval("let const x y = x in const 3 4"), 3);
+        // Scott encoded ones = cons 1 ones.
+        assert_eq!(eval("let cons h t c n = c h t in letrec ones = cons 1 ones in ones (\\h t. t) 0 (\\h t. h) 0"), 1);
+    }
+
+    #[test]
+    fn constructors_and_case() {
+        assert_eq!(eval("case Just 5 of { Nothing -> 0; Just x -> x }"), 5);
+        assert_eq!(eval("case Nothing of { Just x -> x; _ -> 7 }"), 7);
+        assert_eq!(
+            eval("case Pair 1 2 of { p -> case p of { Pair a b -> b } }"),
+            2
+        );
+        // Partially applied constructors are functions.
+        assert_eq!(
+            eval("let cons1 = Cons 1 in case cons1 Nil of { Cons h t -> h }"),
+            1
+        );
+        let length =
+            "letrec length xs = case xs of { Nil -> 0; Cons h t -> Succ (length t) } in length";
+        assert!(parse(length).is_err());
+        let first =
+            "letrec ones = Cons 1 ones in case ones of { Cons h t -> case t of { Cons h t -> h } }";
+        assert_eq!(eval(first), 1);
+        let t = parse("Cons 1 Nil").unwrap();
+        assert_eq!(t.try_force().unwrap().to_string(), "Cons 1 Nil");
+        assert!(parse("case Nil of { Cons h -> h }").is_err());
+        assert!(parse("case True of { False -> 0 }")
+            .unwrap()
+            .try_force()
+            .is_err());
+        assert_eq!(eval("case 5 of { x -> x }"), 5);
+    }
+
+    // The first alternative which matches is taken, like in Haskell.
+    #[test]
+    fn alternatives_in_order() {
+        assert_eq!(eval("case Nil of { _ -> 1; Nil -> 0 }"), 1);
+        assert_eq!(eval("case Nil of { Nil -> 0; Nil -> 1 }"), 0);
+        assert_eq!(eval("case Nil of { Cons h t -> 0; x -> 1; y -> 2 }"), 1);
+        assert_eq!(
+            eval("case Just 2 of { Nothing -> 0; x -> 1; Just y -> y }"),
+            1
+        );
+    }
+
+    #[test]
+    fn primops_and_operators() {
+        assert_eq!(eval("1 + 2 * 3 - 4"), 3);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("10 - 3 - 2"), 5);
+        assert_eq!(eval("div 7 2 + mod 7 2"), 4);
+        assert_eq!(eval("if 1 + 1 == 2 then 1 else 0"), 1);
+        assert_eq!(eval("let inc = (+) 1 in inc (inc 0)"), 2);
+        assert_eq!(
+            eval("letrec fact n = if n == 0 then 1 else n * fact (n - 1) in fact 10"),
+            3628800
+        );
+        // Primops can be shadowed.
+        assert_eq!(eval("let div x y = x in div 7 2"), 7);
+        assert_eq!(eval("(\\mod. mod) 3"), 3);
+        assert!(parse("1 +").is_err());
+        assert!(parse("1 ++ 2").is_err());
+        assert!(parse("2147483647 + 1").unwrap().try_force().is_err());
+    }
+
+    // Terms are printed so that they parse back to themselves.
+    #[test]
+    fn prints_terms() {
+        for src in [
+            "\\x. \\y. x",
+            "f (g x) (\\y. y) Nil",
+            "1 + 2 * 3 == 7",
+            "(1 + 2) * (3 - (4 - 5))",
+            "(+) 1",
+            "let x = 1 in letrec f = \\y. f y in f x",
+            "(case xs of { Nil -> 0; Cons h t -> h; _ -> 1 }) + 1",
+        ] {
+            let term = parse_term(src).unwrap();
+            assert_eq!(term.to_string(), src);
+        }
+        assert_eq!(Term::Int(-3).to_string(), "(0 - 3)");
+        // Negative numbers are printed as subtractions, which evaluate back to them.
+        for n in [i32::MIN, i32::MIN + 1, -1, 0, i32::MAX] {
+            assert_eq!(eval(&Term::Int(n).to_string()), n);
+        }
+        assert_eq!(Term::Int(i32::MIN).to_string(), "(0 - 2147483647 - 1)");
+    }
+
+    #[test]
+    fn parses_top_level_declarations() {
+        assert_eq!(
+            parse_decl("let x = 1").unwrap(),
+            Decl::Let(false, "x".to_string(), Term::Int(1))
+        );
+        assert_eq!(
+            parse_decl("let x = 1 in x").unwrap(),
+            Decl::Expr(parse_term("let x = 1 in x").unwrap())
+        );
+        assert_eq!(
+            parse_decl("letrec f x = f x").unwrap(),
+            Decl::Let(true, "f".to_string(), parse_term("\\x. f x").unwrap())
+        );
+        assert_eq!(
+            parse_decl("x").unwrap(),
+            Decl::Expr(Term::Var("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(parse("(\\x. x").is_err());
+        assert!(parse("\\x x").is_err());
+        assert!(parse("1 )").is_err());
+        assert!(parse("99999999999").is_err());
+        assert_eq!(parse("\\x. y").err().unwrap().msg, "unbound variable y");
+        assert_eq!(
+            parse("let x = x in x").err().unwrap().msg,
+            "unbound variable x"
+        );
+        assert!(parse("letrec x = x in x").unwrap().try_force().is_err());
+    }
+
+    // Deeply nested terms are rejected instead of overflowing the stack, with or without parentheses.
+    #[test]
+    fn limits_nesting() {
+        let deep = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(eval(&deep(50)), 1);
+        assert!(parse(&deep(100_000)).err().unwrap().msg.contains("nested"));
+        let long = |n| format!("(\\x. x) {}", "(\\x. x) ".repeat(n));
+        assert!(parse_term(&long(50)).is_ok());
+        assert!(parse(&long(100_000)).err().unwrap().msg.contains("nested"));
+    }
+}
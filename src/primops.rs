@@ -0,0 +1,204 @@
+// Primitive operations, built into the runtime.
+// Adding numbers with a `lambda` means forcing the arguments from inside a Rust closure, i.e. on the Rust stack,
+// and with no way to report errors. Primops instead declare their arity and which arguments they need evaluated.
+// A saturated application is a single HeapObj::PrimApp, the evaluator forces its strict arguments
+// and then calls the operation on them.
+use crate::{bool, i32, EvalError, HeapPtr, Value};
+
+pub struct PrimOp {
+    pub name: &'static str,
+    // Which arguments are evaluated (to weak head normal form) before `code` is called. Its length is the arity.
+    pub strict: &'static [bool],
+    // Gets all the arguments and returns the result, which the evaluator evaluates further.
+    pub code: fn(&[HeapPtr]) -> Result<HeapPtr, EvalError>,
+}
+
+impl PrimOp {
+    pub fn arity(&self) -> usize {
+        self.strict.len()
+    }
+}
+
+pub static ADD: PrimOp = PrimOp {
+    name: "+",
+    strict: &[true, true],
+    code: |args| arith("+", args, i32::checked_add),
+};
+pub static SUB: PrimOp = PrimOp {
+    name: "-",
+    strict: &[true, true],
+    code: |args| arith("-", args, i32::checked_sub),
+};
+pub static MUL: PrimOp = PrimOp {
+    name: "*",
+    strict: &[true, true],
+    code: |args| arith("*", args, i32::checked_mul),
+};
+// div and mod round towards negative infinity, like Haskell's.
+pub static DIV: PrimOp = PrimOp {
+    name: "div",
+    strict: &[true, true],
+    code: |args| {
+        arith("div", args, |a, b| {
+            let q = a.checked_div(b)?;
+            Some(if a % b != 0 && (a < 0) != (b < 0) {
+                q - 1
+            } else {
+                q
+            })
+        })
+    },
+};
+pub static MOD: PrimOp = PrimOp {
+    name: "mod",
+    strict: &[true, true],
+    code: |args| {
+        arith("mod", args, |a, b| {
+            let r = a.checked_rem(b)?;
+            Some(if r != 0 && (r < 0) != (b < 0) {
+                r + b
+            } else {
+                r
+            })
+        })
+    },
+};
+pub static EQ: PrimOp = PrimOp {
+    name: "==",
+    strict: &[true, true],
+    code: |args| Ok(bool(int(&args[0])? == int(&args[1])?)),
+};
+pub static NE: PrimOp = PrimOp {
+    name: "/=",
+    strict: &[true, true],
+    code: |args| Ok(bool(int(&args[0])? != int(&args[1])?)),
+};
+pub static LT: PrimOp = PrimOp {
+    name: "<",
+    strict: &[true, true],
+    code: |args| Ok(bool(int(&args[0])? < int(&args[1])?)),
+};
+pub static LE: PrimOp = PrimOp {
+    name: "<=",
+    strict: &[true, true],
+    code: |args| Ok(bool(int(&args[0])? <= int(&args[1])?)),
+};
+pub static GT: PrimOp = PrimOp {
+    name: ">",
+    strict: &[true, true],
+    code: |args| Ok(bool(int(&args[0])? > int(&args[1])?)),
+};
+pub static GE: PrimOp = PrimOp {
+    name: ">=",
+    strict: &[true, true],
+    code: |args| Ok(bool(int(&args[0])? >= int(&args[1])?)),
+};
+// seq a b evaluates a and continues with b.
+pub static SEQ: PrimOp = PrimOp {
+    name: "seq",
+    strict: &[true, false],
+    code: |args| Ok(args[1].clone()),
+};
+
+pub static PRIMOPS: [&PrimOp; 12] = [
+    &ADD, &SUB, &MUL, &DIV, &MOD, &EQ, &NE, &LT, &LE, &GT, &GE, &SEQ,
+];
+
+pub fn lookup(name: &str) -> Option<&'static PrimOp> {
+    PRIMOPS.iter().find(|op| op.name == name).copied()
+}
+
+// A strict argument is already evaluated, but it may not be an integer.
+fn int(arg: &HeapPtr) -> Result<i32, EvalError> {
+    match arg.value().and_then(Value::i32) {
+        Some(i) => Ok(i),
+        None => Err(EvalError::TypeMismatch {
+            expected: "i32",
+            found: arg.clone(),
+        }),
+    }
+}
+
+fn arith(
+    name: &str,
+    args: &[HeapPtr],
+    f: impl Fn(i32, i32) -> Option<i32>,
+) -> Result<HeapPtr, EvalError> {
+    let (a, b) = (int(&args[0])?, int(&args[1])?);
+    match f(a, b) {
+        Some(n) => Ok(i32(n)),
+        // Only division fails for b == 0.
+        None if b == 0 => Err(EvalError::Arithmetic(format!(
+            "{a} {name} {b}: divide by zero"
+        ))),
+        None => Err(EvalError::Arithmetic(format!("{a} {name} {b}: overflow"))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::primops::{lookup, ADD, EQ, SEQ, SUB};
+    use crate::{ap, i32, lambda, prim, EvalError, HeapPtr, Value};
+
+    fn eval(op: &'static str, a: i32, b: i32) -> Result<i32, EvalError> {
+        prim(lookup(op).unwrap(), vec![i32(a), i32(b)]).try_i32()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("+", 2, 3).unwrap(), 5);
+        assert_eq!(eval("-", 2, 3).unwrap(), -1);
+        assert_eq!(eval("*", 4, 3).unwrap(), 12);
+        assert_eq!(eval("div", 7, 2).unwrap(), 3);
+        assert_eq!(eval("div", -7, 2).unwrap(), -4);
+        assert_eq!(eval("mod", -7, 2).unwrap(), 1);
+        assert_eq!(eval("mod", 7, -2).unwrap(), -1);
+    }
+
+    #[test]
+    fn errors() {
+        let e = eval("+", i32::MAX, 1).err().unwrap();
+        assert_eq!(e.to_string(), "2147483647 + 1: overflow");
+        assert!(matches!(eval("div", 1, 0), Err(EvalError::Arithmetic(_))));
+        assert!(matches!(
+            eval("mod", i32::MIN, -1),
+            Err(EvalError::Arithmetic(_))
+        ));
+        let t = prim(&ADD, vec![i32(1), lambda(|x| x)]);
+        assert!(matches!(
+            t.try_force(),
+            Err(EvalError::TypeMismatch {
+                expected: "i32",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn arguments_are_evaluated_once() {
+        // x = (\y. y) 5; x - x
+        let x = ap(&lambda(|y| y), &i32(5));
+        let t = prim(&SUB, vec![x.clone(), x.clone()]);
+        assert_eq!(t.try_i32().unwrap(), 0);
+        assert_eq!(x.value().and_then(Value::i32), Some(5));
+        // Comparisons return booleans.
+        let t: HeapPtr = prim(&EQ, vec![x.clone(), i32(5)]);
+        assert_eq!(t.try_force().unwrap().to_string(), "True");
+    }
+
+    #[test]
+    fn lazy_arguments_and_partial_application() {
+        // seq 1 (1 2) fails only when the second argument is evaluated.
+        let t = prim(&SEQ, vec![i32(1), ap(&i32(1), &i32(2))]);
+        assert!(matches!(t.try_force(), Err(EvalError::NotAFunction(_))));
+        // (+ 1) is a function.
+        let inc = prim(&ADD, vec![i32(1)]);
+        assert_eq!(ap(&inc, &i32(2)).try_i32().unwrap(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "+ takes 2 arguments")]
+    fn too_many_arguments() {
+        prim(&ADD, vec![i32(1), i32(2), i32(3)]);
+    }
+}
@@ -0,0 +1,246 @@
+// Reading values back as lambda terms, closures included (normalization by evaluation).
+//
+//   let fst = lambda(|x| lambda(move |_| x.clone()));
+//   readback(&fst)?.to_string() == "\\x. \\y. x"
+//
+// A closure is opaque Rust code, we can't look at its body. But we can call it: applied to a free variable
+// it returns its body, which we force and read back in turn. Constructors are read back field by field.
+// The result is a parser::Term, printed in the surface syntax, so the parser reads it back in
+// (unless it is nested deeper than the parser allows).
+//
+// Everything is evaluated, also under lambdas and in constructor fields, and the term is a tree, sharing is lost.
+// HeapPtr::normalize evaluates the same way in the heap, where sharing is kept.
+// A value with an infinite normal form, e.g. `ones`, would be read back forever, so we stop at `max_depth`
+// nested lambdas and constructors, MAX_DEPTH for `readback`.
+//
+// When evaluation needs to know what a variable is, e.g. `\f. f 1` applies one, it stops at a neutral term
+// (see NeutralObj), which is read back as it is: `\x. x 1`.
+// Rust closures which look at their arguments themselves (e.g. force them to an integer) panic on a variable,
+// readback is meant for closures made by the parser or with `lambda_env` and combinators.
+use std::rc::Rc;
+
+use crate::parser::{Pat, Term};
+use crate::{var, Elim, EvalError, EvalLimits, EvalStrategy, HeapPtr, Target};
+
+// Each level takes some Rust stack, for reading back and then for printing the term, which is recursive too:
+// about 2 KB in a debug build. This is enough for a list of 800 elements and fits in the 2 MB stack of a thread
+// (or a test), with a bigger stack readback_with can go deeper.
+pub const MAX_DEPTH: usize = 800;
+
+pub fn readback(ptr: &HeapPtr) -> Result<Term, EvalError> {
+    readback_with(ptr, EvalStrategy::Need, EvalLimits::default(), MAX_DEPTH)
+}
+
+// Evaluates with the strategy and the limits, as HeapPtr::try_force_with does.
+pub fn readback_with(
+    ptr: &HeapPtr,
+    strategy: EvalStrategy,
+    limits: EvalLimits,
+    max_depth: usize,
+) -> Result<Term, EvalError> {
+    Reader {
+        strategy,
+        limits,
+        max_depth,
+    }
+    .read(ptr, 0, 0)
+}
+
+// Variables are named after their level: x, y and z, then x3, x4 and so on.
+pub(crate) fn name(level: u32) -> String {
+    match level {
+        0 => "x".to_string(),
+        1 => "y".to_string(),
+        2 => "z".to_string(),
+        _ => format!("x{level}"),
+    }
+}
+
+struct Reader {
+    strategy: EvalStrategy,
+    limits: EvalLimits,
+    max_depth: usize,
+}
+
+impl Reader {
+    // `level` is the number of lambdas around `ptr`, i.e. the next free variable.
+    fn read(&self, ptr: &HeapPtr, level: u32, depth: usize) -> Result<Term, EvalError> {
+        if depth >= self.max_depth {
+            return Err(EvalError::ResourceExhausted("readback depth"));
+        }
+        let value = ptr.try_force_with(self.strategy, self.limits, |_| {})?;
+        let term = match value.ptr.target() {
+            Target::I32(n) => Term::Int(n),
+            Target::Var(x) => Term::Var(name(x)),
+            Target::Neutral(_) => {
+                let (x, spine) = value.clone().neutral().expect("a neutral term");
+                spine.iter().try_fold(Term::Var(name(x)), |head, elim| {
+                    self.read_elim(head, elim, level, depth + 1)
+                })?
+            }
+            Target::Nullary(tag) => Term::Con(tag),
+            // Cons h t is (Cons h) t.
+            Target::Con(con) => {
+                let mut term = Term::Con(con.tag);
+                for field in &con.fields {
+                    let field = self.read(field, level, depth + 1)?;
+                    term = Term::App(Rc::new(term), Rc::new(field));
+                }
+                term
+            }
+            // \x y. body is \x. \y. body
+            Target::Closure(closure) => {
+                let params = level..level + closure.arity as u32;
+                let vars: Vec<HeapPtr> = params.clone().map(var).collect();
+                let body = self.read(&closure.call(&vars), params.end, depth + 1)?;
+                params
+                    .rev()
+                    .fold(body, |body, x| Term::Lam(name(x), Rc::new(body)))
+            }
+            Target::Cell(_) | Target::Arena(_) => unreachable!("values are not in cells"),
+        };
+        Ok(term)
+    }
+
+    // The neutral term `head` with what was to be done with it.
+    fn read_elim(
+        &self,
+        head: Term,
+        elim: &Elim,
+        level: u32,
+        depth: usize,
+    ) -> Result<Term, EvalError> {
+        let term = match elim {
+            Elim::App(arg) => Term::App(Rc::new(head), Rc::new(self.read(arg, level, depth)?)),
+            // The branches are read back like lambdas, they bind the fields.
+            Elim::Case(alts) => {
+                let mut branches = vec![];
+                for (tag, branch) in alts.open(level) {
+                    let (pat, fields) = match tag {
+                        Some(tag) => (
+                            Pat::Con(tag, (level..level + tag.arity as u32).map(name).collect()),
+                            tag.arity,
+                        ),
+                        None => (Pat::Var("_".to_string()), 0),
+                    };
+                    branches.push((
+                        pat,
+                        Rc::new(self.read(&branch, level + fields as u32, depth)?),
+                    ));
+                }
+                Term::Case(Rc::new(head), branches)
+            }
+            // `(+) x 1` is x + 1.
+            Elim::Prim(op, args, i) => {
+                let mut head = Some(head);
+                args.iter().enumerate().try_fold(
+                    Term::Var(op.name.to_string()),
+                    |term, (j, arg)| {
+                        let arg = if j == *i {
+                            head.take().expect("one neutral argument")
+                        } else {
+                            self.read(arg, level, depth)?
+                        };
+                        Ok(Term::App(Rc::new(term), Rc::new(arg)))
+                    },
+                )?
+            }
+        };
+        Ok(term)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::{parse, parse_term};
+    use crate::readback::{readback, readback_with};
+    use crate::{
+        ap, comb, con, i32, lambda, lambda_n, letrec, Combinator, EvalError, EvalLimits,
+        EvalStrategy, CONS,
+    };
+
+    fn read(src: &str) -> String {
+        readback(&parse(src).unwrap()).unwrap().to_string()
+    }
+
+    #[test]
+    fn closures_are_read_back() {
+        // fst = \x.\y.x
+        let fst = lambda(move |x| lambda(move |_| x.clone()));
+        assert_eq!(readback(&fst).unwrap().to_string(), "\\x. \\y. x");
+        // A closure of arity 2 and a partial application of one of arity 3.
+        let k = lambda_n(vec![], 2, |_, args| args[0].clone());
+        assert_eq!(readback(&k).unwrap().to_string(), "\\x. \\y. x");
+        let f = lambda_n(vec![], 3, |_, args| args[2].clone());
+        assert_eq!(
+            readback(&ap(&f, &i32(1))).unwrap().to_string(),
+            "\\x. \\y. y"
+        );
+        // Combinators are closures too.
+        static K: Combinator = Combinator {
+            name: "K",
+            arity: 2,
+            code: |_, args| args[0].clone(),
+        };
+        assert_eq!(
+            readback(&ap(&comb(&K, vec![]), &i32(5)))
+                .unwrap()
+                .to_string(),
+            "\\x. 5"
+        );
+    }
+
+    #[test]
+    fn bodies_are_evaluated() {
+        assert_eq!(read("\\x y. Pair y x"), "\\x. \\y. Pair y x");
+        assert_eq!(read("let k = \\a b. a in k (\\x. x)"), "\\x. \\y. y");
+        assert_eq!(read("\\x. 1 + 2"), "\\x. 3");
+        assert_eq!(
+            read("\\x. Just (Cons x (Cons (0 - 1) Nil))"),
+            "\\x. Just (Cons x (Cons (0 - 1) Nil))"
+        );
+        // The term is in the surface syntax.
+        let term = readback(&parse("\\f. Just \\x. f").unwrap()).unwrap();
+        assert_eq!(parse_term(&term.to_string()).unwrap(), term);
+    }
+
+    #[test]
+    fn long_lists() {
+        let range = "letrec range a b = if a > b then Nil else Cons a (range (a + 1) b) in range 1";
+        let list = read(&format!("{range} 600"));
+        assert!(list.starts_with("Cons 1 (Cons 2 ("));
+        assert!(list.contains("(Cons 600 Nil)"));
+        assert_eq!(list.matches("Cons").count(), 600);
+        // Each element is a level deeper.
+        let list = parse(&format!("{range} 600")).unwrap();
+        assert!(matches!(
+            readback_with(&list, EvalStrategy::Need, EvalLimits::default(), 100),
+            Err(EvalError::ResourceExhausted(_))
+        ));
+    }
+
+    #[test]
+    fn stuck_terms() {
+        assert_eq!(read("\\f. f (1 + 2) 4"), "\\x. x 3 4");
+        assert_eq!(read("\\a b. a + b * 2"), "\\x. \\y. x + y * 2");
+        assert_eq!(
+            read("\\xs. case xs of { Nil -> 0; Cons h t -> h; _ -> 1 }"),
+            "\\x. case x of { Nil -> 0; Cons y z -> y; _ -> 1 }"
+        );
+        // The branches are evaluated too, with the fields unknown.
+        assert_eq!(
+            read("\\p. case p of { Pair a b -> (\\c. c) b }"),
+            "\\x. case x of { Pair y z -> z }"
+        );
+    }
+
+    #[test]
+    fn infinite_terms() {
+        // ones = Cons 1 ones
+        let ones = letrec(|ones| con(&CONS, vec![i32(1), ones.clone()]));
+        assert!(matches!(
+            readback(&ones),
+            Err(EvalError::ResourceExhausted(_))
+        ));
+    }
+}
@@ -0,0 +1,217 @@
+// Counters of what the runtime does on this thread, e.g. for the benchmarks in benches/programs.rs.
+//
+// Counting is off until `enable` is called. Only the number of live objects is always kept track of,
+// since it has to see every allocation and every free.
+use std::cell::{Cell, RefCell};
+
+use crate::{HeapObj, HeapPtr};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    // Objects allocated, by kind. Unboxed i32s and constructors without fields are not allocated.
+    pub allocated: Allocations,
+    // Thunks (App, Case, PrimApp) entered by the evaluator.
+    pub forced: usize,
+    // Thunks overwritten with their value.
+    pub updates: usize,
+    // Closures called by the evaluator.
+    pub calls: usize,
+    // Thunks demanded (forced, applied, scrutinized, passed to a primop) which were evaluated before, so their
+    // value is reused. Literals, lambdas and constructors are values from the start, they don't count.
+    pub memo_hits: usize,
+    // The most frames on the evaluator's stack.
+    pub max_depth: usize,
+    // Heap objects alive now, and the most of them alive at once.
+    pub live: usize,
+    pub peak_live: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Allocations {
+    pub app: usize,
+    pub closure: usize,
+    pub con: usize,
+    pub case: usize,
+    pub prim_app: usize,
+    pub pap: usize,
+    pub black_hole: usize,
+    pub ind: usize,
+    pub neutral: usize,
+}
+
+impl Allocations {
+    const ZERO: Allocations = Allocations {
+        app: 0,
+        closure: 0,
+        con: 0,
+        case: 0,
+        prim_app: 0,
+        pap: 0,
+        black_hole: 0,
+        ind: 0,
+        neutral: 0,
+    };
+
+    pub fn total(&self) -> usize {
+        self.app
+            + self.closure
+            + self.con
+            + self.case
+            + self.prim_app
+            + self.pap
+            + self.black_hole
+            + self.ind
+            + self.neutral
+    }
+}
+
+impl Stats {
+    const ZERO: Stats = Stats {
+        allocated: Allocations::ZERO,
+        forced: 0,
+        updates: 0,
+        calls: 0,
+        memo_hits: 0,
+        max_depth: 0,
+        live: 0,
+        peak_live: 0,
+    };
+}
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static STATS: RefCell<Stats> = const { RefCell::new(Stats::ZERO) };
+}
+
+pub fn enable() {
+    ENABLED.set(true);
+}
+
+pub fn disable() {
+    ENABLED.set(false);
+}
+
+pub fn get() -> Stats {
+    STATS.with_borrow(|stats| *stats)
+}
+
+// Starts counting from zero. Objects alive now stay alive, so they are counted in `live` and `peak_live`.
+pub fn reset() {
+    STATS.with_borrow_mut(|stats| {
+        *stats = Stats {
+            live: stats.live,
+            peak_live: stats.live,
+            ..Stats::ZERO
+        }
+    })
+}
+
+fn count(f: impl FnOnce(&mut Stats)) {
+    if ENABLED.get() {
+        STATS.with_borrow_mut(f)
+    }
+}
+
+pub(crate) fn allocated(obj: &HeapObj) {
+    allocation(|kinds| match obj {
+        HeapObj::App(_, _) => &mut kinds.app,
+        HeapObj::Value(_) => unreachable!("values are not allocated in a cell"),
+        HeapObj::Case(_, _) => &mut kinds.case,
+        HeapObj::PrimApp(_, _) => &mut kinds.prim_app,
+        HeapObj::Pap(_, _) => &mut kinds.pap,
+        HeapObj::BlackHole => &mut kinds.black_hole,
+        HeapObj::Ind(_) => &mut kinds.ind,
+    })
+}
+
+// Closures, constructors and neutral terms are allocated on their own, see Closure::new, `con` and `stuck`.
+pub(crate) fn allocated_closure() {
+    allocation(|kinds| &mut kinds.closure)
+}
+
+pub(crate) fn allocated_con() {
+    allocation(|kinds| &mut kinds.con)
+}
+
+pub(crate) fn allocated_neutral() {
+    allocation(|kinds| &mut kinds.neutral)
+}
+
+fn allocation(kind: impl FnOnce(&mut Allocations) -> &mut usize) {
+    STATS.with_borrow_mut(|stats| {
+        stats.live += 1;
+        stats.peak_live = stats.peak_live.max(stats.live);
+    });
+    count(|stats| *kind(&mut stats.allocated) += 1)
+}
+
+pub(crate) fn freed() {
+    STATS.with_borrow_mut(|stats| stats.live -= 1)
+}
+
+pub(crate) fn forced() {
+    count(|stats| stats.forced += 1)
+}
+
+pub(crate) fn updated() {
+    count(|stats| stats.updates += 1)
+}
+
+pub(crate) fn called() {
+    count(|stats| stats.calls += 1)
+}
+
+pub(crate) fn demanded(ptr: &HeapPtr) {
+    count(|stats| {
+        if ptr.was_thunk() && ptr.is_evaluated() {
+            stats.memo_hits += 1;
+        }
+    })
+}
+
+pub(crate) fn depth(depth: usize) {
+    count(|stats| stats.max_depth = stats.max_depth.max(depth))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ap, i32, lambda, stats};
+
+    #[test]
+    fn counts_allocations_and_forcing() {
+        stats::enable();
+        stats::reset();
+        let id = lambda(|x| x);
+        let t = ap(&id, &i32(5));
+        let allocated = stats::get().allocated;
+        // The 5 is unboxed, it is not allocated.
+        assert_eq!(
+            (allocated.closure, allocated.app, allocated.total()),
+            (1, 1, 2)
+        );
+        t.force();
+        t.force();
+        // Only the first force enters the thunk and updates it, the second one reuses the value.
+        let counts = stats::get();
+        assert_eq!((counts.forced, counts.updates, counts.calls), (1, 1, 1));
+        // The second force demands `t`, which is evaluated by then. `id` never was a thunk.
+        assert_eq!(counts.memo_hits, 1);
+        // An update frame and an argument.
+        assert_eq!(counts.max_depth, 2);
+        drop(id);
+        drop(t);
+        // `t` was updated with an indirection to the 5.
+        assert_eq!(stats::get().live, counts.live - 2);
+        assert_eq!(stats::get().peak_live, counts.peak_live);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let t = ap(&lambda(|x| x), &i32(5));
+        t.force();
+        assert_eq!(stats::get().allocated.total(), 0);
+        assert_eq!(stats::get().forced, 0);
+        // Live objects are always counted: `t`, now an indirection to the 5.
+        assert_eq!(stats::get().live, 1);
+    }
+}
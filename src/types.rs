@@ -0,0 +1,569 @@
+// Hindley-Milner type inference for parsed terms (see parser.rs): Algorithm W with let-polymorphism.
+//
+//   let k = parse_term("\\x y. x")?;
+//   infer(&k, &TypeEnv::default())?.to_string() == "a -> b -> a"
+//
+// The runtime is untyped: the evaluator finds out that it was asked to apply an integer or to add a closure only
+// when it gets there, and fails with EvalError::NotAFunction or TypeMismatch. A term which has a type never fails
+// that way, so checking it before it is compiled reports these errors before anything is allocated.
+// Evaluation of a well typed term can still loop, find no alternative of a case which matches, or overflow.
+//
+// Types are Int, functions and the data types of crate::CONSTRUCTORS: Bool, List a, Pair a b, Maybe a and Unit.
+// Variables bound by `let` and `letrec` are polymorphic, lambda parameters and pattern variables are not.
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::parser::{Pat, Term};
+use crate::primops::{self, PrimOp};
+use crate::Tag;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Var(u32),
+    // A data type applied to its parameters, or Int.
+    Con(&'static str, Vec<Type>),
+    Fun(Box<Type>, Box<Type>),
+}
+
+// A polymorphic type: `vars` may be replaced by any types, e.g. forall a. a -> a.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scheme {
+    pub vars: Vec<u32>,
+    pub ty: Type,
+}
+
+// Types of the global variables, e.g. the definitions of the REPL.
+#[derive(Clone, Default)]
+pub struct TypeEnv(Vec<(String, Option<Scheme>)>);
+
+impl TypeEnv {
+    // `scheme` is None for a global which is not well typed. Terms using it are not well typed either.
+    pub fn extend(&self, name: &str, scheme: Option<Scheme>) -> TypeEnv {
+        let mut env = self.clone();
+        env.0.push((name.to_string(), scheme));
+        env
+    }
+
+    fn mono(&self, name: &str, ty: Type) -> TypeEnv {
+        self.extend(name, Some(Scheme { vars: vec![], ty }))
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Option<Scheme>> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(x, _)| x == name)
+            .map(|(_, scheme)| scheme)
+    }
+
+    fn apply(&self, s: &Subst) -> TypeEnv {
+        let vars = self
+            .0
+            .iter()
+            .map(|(x, scheme)| (x.clone(), scheme.as_ref().map(|scheme| scheme.apply(s))));
+        TypeEnv(vars.collect())
+    }
+
+    fn free_vars(&self, out: &mut Vec<u32>) {
+        for scheme in self.0.iter().filter_map(|(_, scheme)| scheme.as_ref()) {
+            scheme.free_vars(out);
+        }
+    }
+}
+
+// Why a term has no type. The term is the one the types didn't agree in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeError {
+    Mismatch {
+        term: Rc<Term>,
+        expected: Type,
+        found: Type,
+    },
+    // A type which would have to contain itself, e.g. of `x` in `\x. x x`.
+    Infinite {
+        term: Rc<Term>,
+        var: Type,
+        ty: Type,
+    },
+    Unbound(String),
+    // A global which is not well typed, see TypeEnv::extend.
+    Untyped(String),
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeError::Mismatch {
+                term,
+                expected,
+                found,
+            } => {
+                let names = names(&[expected, found]);
+                write!(f, "type mismatch in `{term}`: expected ")?;
+                expected.fmt_prec(f, &names, 0)?;
+                write!(f, ", found ")?;
+                found.fmt_prec(f, &names, 0)
+            }
+            TypeError::Infinite { term, var, ty } => {
+                let names = names(&[var, ty]);
+                write!(f, "infinite type in `{term}`: ")?;
+                var.fmt_prec(f, &names, 0)?;
+                write!(f, " = ")?;
+                ty.fmt_prec(f, &names, 0)
+            }
+            TypeError::Unbound(x) => write!(f, "unbound variable {x}"),
+            TypeError::Untyped(x) => write!(f, "{x} is not well typed"),
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+// The type of a closed term, or of one using the globals in `env`. It is as general as it can be.
+pub fn infer(term: &Term, env: &TypeEnv) -> Result<Scheme, TypeError> {
+    let mut infer = Infer { next: 0 };
+    let (_, ty) = infer.infer(env, term)?;
+    Ok(generalize(&TypeEnv::default(), ty))
+}
+
+// Checks that the term has the type `expected`, or a more general one: `\x. x` is also Int -> Int.
+pub fn check(term: &Term, env: &TypeEnv, expected: &Type) -> Result<(), TypeError> {
+    let mut infer = Infer { next: 0 };
+    let (s, ty) = infer.infer(env, term)?;
+    infer.expect(term, expected, &ty, &s).map(|_| ())
+}
+
+pub(crate) fn int() -> Type {
+    Type::Con("Int", vec![])
+}
+
+pub(crate) fn fun(a: Type, b: Type) -> Type {
+    Type::Fun(Box::new(a), Box::new(b))
+}
+
+// Primops work on integers, except for seq.
+fn primop(op: &PrimOp) -> Scheme {
+    let bool = Type::Con("Bool", vec![]);
+    let ty = match op.name {
+        "seq" => fun(Type::Var(0), fun(Type::Var(1), Type::Var(1))),
+        "==" | "/=" | "<" | "<=" | ">" | ">=" => fun(int(), fun(int(), bool)),
+        _ => fun(int(), fun(int(), int())),
+    };
+    generalize(&TypeEnv::default(), ty)
+}
+
+// A constructor is a curried function of its fields, e.g. Cons : a -> List a -> List a.
+fn constructor(tag: Tag) -> Scheme {
+    let (a, b) = (Type::Var(0), Type::Var(1));
+    let list = Type::Con("List", vec![a.clone()]);
+    let maybe = Type::Con("Maybe", vec![a.clone()]);
+    let (data, fields) = match tag.name {
+        "False" | "True" => (Type::Con("Bool", vec![]), vec![]),
+        "Nil" => (list, vec![]),
+        "Cons" => (list.clone(), vec![a, list]),
+        "Pair" => (Type::Con("Pair", vec![a.clone(), b.clone()]), vec![a, b]),
+        "Nothing" => (maybe, vec![]),
+        "Just" => (maybe, vec![a]),
+        "Unit" => (Type::Con("Unit", vec![]), vec![]),
+        name => unreachable!("constructor {name} has no type"),
+    };
+    let ty = fields
+        .into_iter()
+        .rev()
+        .fold(data, |ty, field| fun(field, ty));
+    generalize(&TypeEnv::default(), ty)
+}
+
+// What the type variables stand for, found so far.
+#[derive(Default)]
+struct Subst(HashMap<u32, Type>);
+
+impl Subst {
+    fn single(var: u32, ty: Type) -> Subst {
+        Subst(HashMap::from([(var, ty)]))
+    }
+
+    // Applying the result is applying `other` and then self.
+    fn after(&self, other: &Subst) -> Subst {
+        let mut s: HashMap<u32, Type> = other
+            .0
+            .iter()
+            .map(|(&var, ty)| (var, ty.apply(self)))
+            .collect();
+        for (&var, ty) in &self.0 {
+            s.entry(var).or_insert_with(|| ty.clone());
+        }
+        Subst(s)
+    }
+}
+
+impl Type {
+    fn apply(&self, s: &Subst) -> Type {
+        match self {
+            Type::Var(var) => s.0.get(var).cloned().unwrap_or_else(|| self.clone()),
+            Type::Con(name, args) => Type::Con(name, args.iter().map(|arg| arg.apply(s)).collect()),
+            Type::Fun(a, b) => fun(a.apply(s), b.apply(s)),
+        }
+    }
+
+    // In order of first appearance.
+    fn free_vars(&self, out: &mut Vec<u32>) {
+        match self {
+            Type::Var(var) if !out.contains(var) => out.push(*var),
+            Type::Var(_) => {}
+            Type::Con(_, args) => args.iter().for_each(|arg| arg.free_vars(out)),
+            Type::Fun(a, b) => {
+                a.free_vars(out);
+                b.free_vars(out);
+            }
+        }
+    }
+}
+
+impl Scheme {
+    fn apply(&self, s: &Subst) -> Scheme {
+        let free = s.0.iter().filter(|(var, _)| !self.vars.contains(var));
+        let s = Subst(free.map(|(&var, ty)| (var, ty.clone())).collect());
+        Scheme {
+            vars: self.vars.clone(),
+            ty: self.ty.apply(&s),
+        }
+    }
+
+    fn free_vars(&self, out: &mut Vec<u32>) {
+        let mut vars = vec![];
+        self.ty.free_vars(&mut vars);
+        for var in vars {
+            if !self.vars.contains(&var) && !out.contains(&var) {
+                out.push(var);
+            }
+        }
+    }
+}
+
+// The variables of `ty` which are not fixed by the environment can be anything.
+fn generalize(env: &TypeEnv, ty: Type) -> Scheme {
+    let mut fixed = vec![];
+    env.free_vars(&mut fixed);
+    let mut vars = vec![];
+    ty.free_vars(&mut vars);
+    vars.retain(|var| !fixed.contains(var));
+    Scheme { vars, ty }
+}
+
+// Why two types can't be made equal.
+enum UnifyError {
+    Mismatch,
+    Infinite(u32, Type),
+}
+
+// The substitution which makes the types equal.
+fn unify(a: &Type, b: &Type) -> Result<Subst, UnifyError> {
+    match (a, b) {
+        (Type::Var(x), Type::Var(y)) if x == y => Ok(Subst::default()),
+        (Type::Var(x), ty) | (ty, Type::Var(x)) => {
+            let mut vars = vec![];
+            ty.free_vars(&mut vars);
+            if vars.contains(x) {
+                return Err(UnifyError::Infinite(*x, ty.clone()));
+            }
+            Ok(Subst::single(*x, ty.clone()))
+        }
+        (Type::Fun(a1, b1), Type::Fun(a2, b2)) => {
+            let s1 = unify(a1, a2)?;
+            let s2 = unify(&b1.apply(&s1), &b2.apply(&s1))?;
+            Ok(s2.after(&s1))
+        }
+        (Type::Con(x, xs), Type::Con(y, ys)) if x == y && xs.len() == ys.len() => {
+            let mut s = Subst::default();
+            for (a, b) in xs.iter().zip(ys) {
+                s = unify(&a.apply(&s), &b.apply(&s))?.after(&s);
+            }
+            Ok(s)
+        }
+        _ => Err(UnifyError::Mismatch),
+    }
+}
+
+struct Infer {
+    next: u32,
+}
+
+impl Infer {
+    fn fresh(&mut self) -> Type {
+        self.next += 1;
+        Type::Var(self.next - 1)
+    }
+
+    // The quantified variables of the scheme replaced by fresh ones.
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let s = Subst(scheme.vars.iter().map(|&var| (var, self.fresh())).collect());
+        scheme.ty.apply(&s)
+    }
+
+    // Unifies the type `found` of `term` with the one it should have. `s` is what we know so far.
+    fn expect(
+        &self,
+        term: &Term,
+        expected: &Type,
+        found: &Type,
+        s: &Subst,
+    ) -> Result<Subst, TypeError> {
+        let (expected, found) = (expected.apply(s), found.apply(s));
+        match unify(&expected, &found) {
+            Ok(s1) => Ok(s1.after(s)),
+            Err(UnifyError::Mismatch) => Err(TypeError::Mismatch {
+                term: Rc::new(term.clone()),
+                expected,
+                found,
+            }),
+            Err(UnifyError::Infinite(var, ty)) => Err(TypeError::Infinite {
+                term: Rc::new(term.clone()),
+                var: Type::Var(var),
+                ty,
+            }),
+        }
+    }
+
+    // Algorithm W: the type of the term and what was learned about the type variables in `env` on the way.
+    fn infer(&mut self, env: &TypeEnv, term: &Term) -> Result<(Subst, Type), TypeError> {
+        match term {
+            Term::Var(x) => {
+                let scheme = match env.lookup(x) {
+                    Some(Some(scheme)) => scheme.clone(),
+                    Some(None) => return Err(TypeError::Untyped(x.clone())),
+                    None => {
+                        primop(primops::lookup(x).ok_or_else(|| TypeError::Unbound(x.clone()))?)
+                    }
+                };
+                Ok((Subst::default(), self.instantiate(&scheme)))
+            }
+            Term::Int(_) => Ok((Subst::default(), int())),
+            Term::Con(tag) => Ok((Subst::default(), self.instantiate(&constructor(tag)))),
+            Term::Lam(x, body) => {
+                let param = self.fresh();
+                let (s, ty) = self.infer(&env.mono(x, param.clone()), body)?;
+                let param = param.apply(&s);
+                Ok((s, fun(param, ty)))
+            }
+            Term::App(f, a) => {
+                let (s1, tf) = self.infer(env, f)?;
+                let (s2, ta) = self.infer(&env.apply(&s1), a)?;
+                let result = self.fresh();
+                let s = self.expect(term, &fun(ta, result.clone()), &tf, &s2.after(&s1))?;
+                let ty = result.apply(&s);
+                Ok((s, ty))
+            }
+            Term::Let(x, e1, e2) => {
+                let (s1, t1) = self.infer(env, e1)?;
+                let env = env.apply(&s1);
+                let scheme = generalize(&env, t1);
+                let (s2, t2) = self.infer(&env.extend(x, Some(scheme)), e2)?;
+                Ok((s2.after(&s1), t2))
+            }
+            // x is not polymorphic in its own definition.
+            Term::LetRec(x, e1, e2) => {
+                let tx = self.fresh();
+                let (s1, t1) = self.infer(&env.mono(x, tx.clone()), e1)?;
+                let s1 = self.expect(e1, &tx, &t1, &s1)?;
+                let env = env.apply(&s1);
+                let scheme = generalize(&env, t1.apply(&s1));
+                let (s2, t2) = self.infer(&env.extend(x, Some(scheme)), e2)?;
+                Ok((s2.after(&s1), t2))
+            }
+            // The patterns have the type of the scrutinee, the branches the type of the case.
+            Term::Case(scrutinee, alts) => {
+                let (mut s, ts) = self.infer(env, scrutinee)?;
+                let result = self.fresh();
+                for (pat, body) in alts {
+                    let env = match pat {
+                        Pat::Con(tag, vars) => {
+                            let mut ty = self.instantiate(&constructor(tag));
+                            let mut fields = vec![];
+                            while let Type::Fun(field, rest) = ty {
+                                fields.push(*field);
+                                ty = *rest;
+                            }
+                            s = self.expect(scrutinee, &ty, &ts, &s)?;
+                            let env = vars
+                                .iter()
+                                .zip(fields)
+                                .fold(env.clone(), |env, (x, ty)| env.mono(x, ty));
+                            env.apply(&s)
+                        }
+                        Pat::Var(x) => env.mono(x, ts.clone()).apply(&s),
+                    };
+                    let (s1, tb) = self.infer(&env, body)?;
+                    s = self.expect(body, &result, &tb, &s1.after(&s))?;
+                }
+                let ty = result.apply(&s);
+                Ok((s, ty))
+            }
+        }
+    }
+}
+
+// Type variables are named a, b, c... in order of first appearance.
+fn names(types: &[&Type]) -> HashMap<u32, String> {
+    let mut vars = vec![];
+    for ty in types {
+        ty.free_vars(&mut vars);
+    }
+    let name = |i: usize| match i {
+        0..26 => ((b'a' + i as u8) as char).to_string(),
+        _ => format!("t{i}"),
+    };
+    vars.into_iter()
+        .enumerate()
+        .map(|(i, var)| (var, name(i)))
+        .collect()
+}
+
+impl Type {
+    // `prec` is 0 at the top, 1 on the left of an arrow and 2 for an argument of a data type.
+    fn fmt_prec(
+        &self,
+        f: &mut fmt::Formatter,
+        names: &HashMap<u32, String>,
+        prec: u8,
+    ) -> fmt::Result {
+        match self {
+            Type::Var(var) => write!(f, "{}", names[var]),
+            Type::Con(name, args) if args.is_empty() => write!(f, "{name}"),
+            Type::Con(name, args) => {
+                if prec > 1 {
+                    write!(f, "(")?;
+                }
+                write!(f, "{name}")?;
+                for arg in args {
+                    write!(f, " ")?;
+                    arg.fmt_prec(f, names, 2)?;
+                }
+                if prec > 1 {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Type::Fun(a, b) => {
+                if prec > 0 {
+                    write!(f, "(")?;
+                }
+                a.fmt_prec(f, names, 1)?;
+                write!(f, " -> ")?;
+                b.fmt_prec(f, names, 0)?;
+                if prec > 0 {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_prec(f, &names(&[self]), 0)
+    }
+}
+
+// Quantified variables are not written out, as in Haskell.
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.ty)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::{parse, parse_term};
+    use crate::types::{infer, TypeEnv, TypeError};
+
+    fn type_of(src: &str) -> Result<String, String> {
+        let term = parse_term(src).unwrap();
+        infer(&term, &TypeEnv::default())
+            .map(|ty| ty.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn infers_types() {
+        assert_eq!(type_of("\\x y. x").unwrap(), "a -> b -> a");
+        assert_eq!(
+            type_of("\\f g x. f (g x)").unwrap(),
+            "(a -> b) -> (c -> a) -> c -> b"
+        );
+        assert_eq!(type_of("\\x. x + 1 == 2").unwrap(), "Int -> Bool");
+        assert_eq!(type_of("Cons").unwrap(), "a -> List a -> List a");
+        assert_eq!(
+            type_of("\\p. case p of { Pair a b -> Pair b (Just a) }").unwrap(),
+            "Pair a b -> Pair b (Maybe a)"
+        );
+        let length =
+            "letrec length xs = case xs of { Nil -> 0; Cons h t -> 1 + length t } in length";
+        assert_eq!(type_of(length).unwrap(), "List a -> Int");
+        assert_eq!(
+            type_of("if 1 < 2 then Nothing else Just Unit").unwrap(),
+            "Maybe Unit"
+        );
+    }
+
+    #[test]
+    fn let_is_polymorphic() {
+        assert_eq!(
+            type_of("let id x = x in Pair (id 1) (id True)").unwrap(),
+            "Pair Int Bool"
+        );
+        // Lambda parameters are not.
+        assert_eq!(
+            type_of("\\id. Pair (id 1) (id True)").unwrap_err(),
+            "type mismatch in `id True`: expected Bool -> a, found Int -> b"
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(
+            type_of("1 2").unwrap_err(),
+            "type mismatch in `1 2`: expected Int -> a, found Int"
+        );
+        assert_eq!(
+            type_of("\\x. x x").unwrap_err(),
+            "infinite type in `x x`: a = a -> b"
+        );
+        assert_eq!(
+            type_of("case 1 of { Nil -> 0 }").unwrap_err(),
+            "type mismatch in `1`: expected List a, found Int"
+        );
+        assert_eq!(
+            type_of("\\b. if b then 1 else Nil").unwrap_err(),
+            "type mismatch in `Nil`: expected Int, found List a"
+        );
+        assert!(matches!(
+            infer(&parse_term("y").unwrap(), &TypeEnv::default()),
+            Err(TypeError::Unbound(_))
+        ));
+        let env = TypeEnv::default().extend("bad", None);
+        assert_eq!(
+            infer(&parse_term("bad 1").unwrap(), &env)
+                .unwrap_err()
+                .to_string(),
+            "bad is not well typed"
+        );
+    }
+
+    // Well typed terms don't apply integers or add closures, the evaluator doesn't fail on them.
+    #[test]
+    fn well_typed_terms_evaluate() {
+        for src in [
+            "let compose f g x = f (g x) in compose (\\x. x * 2) (\\x. x + 1) 5",
+            "letrec sum xs = case xs of { Nil -> 0; Cons h t -> h + sum t } in sum (Cons 1 (Cons 2 Nil))",
+            "let pair = Pair (\\x. x) 1 in case pair of { Pair f n -> f n }",
+            // A default alone matches anything, not only constructors.
+            "case 5 of { x -> x }",
+        ] {
+            assert!(type_of(src).is_ok(), "{src}");
+            assert!(parse(src).unwrap().try_i32().is_ok(), "{src}");
+        }
+    }
+}